@@ -0,0 +1,121 @@
+use serde::{Serialize, Deserialize};
+use websocket::OwnedMessage;
+use websocket::server::upgrade::r#async::Upgrade;
+use tokio::net::TcpStream;
+use futures::{Future, Sink, Stream};
+
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+use crate::profile::{ProfileInfo, ProfileStore};
+use crate::session::Sessions;
+
+/// Profile management requests, accepted on `/admin/<admin token>`.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "request")]
+pub enum AdminRequest {
+    CreateProfile { name: String },
+    ListProfiles,
+    RenameProfile { public_key: String, name: String },
+    DeleteProfile { public_key: String },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "response")]
+pub enum AdminResponse {
+    Ok,
+    Profile { profile: ProfileInfo },
+    NewProfile { profile: ProfileInfo, secret_key: String },
+    ProfileList { profiles: Vec<ProfileInfo> },
+    ProfileNotFoundError,
+    ProfileStorageError { message: String },
+    InvalidRequestError { message: String },
+}
+
+impl From<IoError> for AdminResponse {
+    fn from(e: IoError) -> Self {
+        match e.kind() {
+            IoErrorKind::NotFound => AdminResponse::ProfileNotFoundError,
+            _ => AdminResponse::ProfileStorageError {
+                message: format!("{}", e)
+            },
+        }
+    }
+}
+
+fn run_request(
+    store: &ProfileStore,
+    sessions: &Sessions,
+    request: AdminRequest
+) -> Result<AdminResponse, IoError> {
+    use AdminRequest as R;
+
+    let response = match request {
+        R::CreateProfile { name } => {
+            let profile = store.create(name)?;
+
+            AdminResponse::NewProfile {
+                profile: profile.info,
+                secret_key: profile.secret_key
+            }
+        },
+        R::ListProfiles => AdminResponse::ProfileList {
+            profiles: store.list()?
+        },
+        R::RenameProfile { public_key, name } => AdminResponse::Profile {
+            profile: store.rename(&public_key, name)?
+        },
+        R::DeleteProfile { public_key } => {
+            let secret_key = store.delete(&public_key)?;
+            sessions.remove(&secret_key);
+
+            AdminResponse::Ok
+        },
+    };
+
+    Ok(response)
+}
+
+fn answer(store: &ProfileStore, sessions: &Sessions, text: &str) -> AdminResponse {
+    match serde_json::from_str(text) {
+        Ok(request) => run_request(store, sessions, request)
+            .unwrap_or_else(AdminResponse::from),
+        Err(e) => AdminResponse::InvalidRequestError {
+            message: format!("{}", e)
+        },
+    }
+}
+
+/// Serve an authorized admin connection.
+pub fn serve(
+    upgrade: Upgrade<TcpStream>,
+    store: ProfileStore,
+    sessions: Sessions
+) -> impl Future<Item = (), Error = IoError> {
+    upgrade
+        .accept()
+        .map_err(|e| IoError::new(IoErrorKind::Other,
+            format!("websocket accept err: {}", e)
+        ))
+        .and_then(move |(s, _h)| {
+            let (sink, stream) = s.split();
+
+            stream
+                .take_while(|m| Ok(!m.is_close()))
+                .filter_map(move |m| match m {
+                    OwnedMessage::Text(t) => {
+                        let response = answer(&store, &sessions, &t);
+                        let response = serde_json::to_string(&response).unwrap();
+
+                        Some(OwnedMessage::Text(response))
+                    },
+                    _ => None,
+                })
+                .map_err(|e| IoError::new(IoErrorKind::Other,
+                    format!("websocket read err: {}", e)
+                ))
+                .forward(sink.sink_map_err(|e| IoError::new(IoErrorKind::Other,
+                    format!("websocket write err: {}", e)
+                )))
+                .map(|_| ())
+        })
+}
@@ -0,0 +1,128 @@
+//! Binary frames carrying file chunks.
+//!
+//! A chunk frame starts with a header of the frame kind byte `1`, the friend
+//! number (`u32`), the file number (`u32`) and the position (`u64`), all in
+//! big endian, followed by the raw chunk data. A client sends chunk frames
+//! instead of `SendFileChunk` requests and, after opting in, receives them
+//! instead of `FileReceiveChunk` events. The response to a chunk frame has
+//! the header fields as its id, e.g.
+//! `{"friend": 0, "file_number": 1, "position": 4096}`.
+
+use websocket::OwnedMessage;
+
+use std::convert::TryInto;
+
+use crate::ext::{ClientRequest, RequestEnvelope, ResponseEnvelope};
+use crate::protocol::{Event, Request};
+
+/// The first byte of a chunk frame. Neither JSON nor MessagePack messages
+/// start with it.
+const CHUNK_FRAME: u8 = 1;
+const HEADER_LEN: usize = 1 + 4 + 4 + 8;
+
+pub fn is_chunk_frame(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&CHUNK_FRAME)
+}
+
+/// Decode a chunk frame into a `SendFileChunk` request, identified by the
+/// header so the client can tell which chunk a response is about.
+pub fn decode(bytes: &[u8]) -> Result<RequestEnvelope, ResponseEnvelope> {
+    if bytes.len() < HEADER_LEN {
+        let message = format!("chunk frame shorter than its {} byte header", HEADER_LEN);
+        return Err(ResponseEnvelope::protocol_error(message))
+    }
+
+    let friend = u32::from_be_bytes(bytes[1..5].try_into().unwrap());
+    let file_number = u32::from_be_bytes(bytes[5..9].try_into().unwrap());
+    let position = u64::from_be_bytes(bytes[9..17].try_into().unwrap());
+    let data = bytes[HEADER_LEN..].to_vec();
+
+    Ok(RequestEnvelope {
+        id: Some(serde_json::json!({
+            "friend": friend,
+            "file_number": file_number,
+            "position": position,
+        })),
+        request: ClientRequest::Tox(Request::SendFileChunk { friend, file_number, position, data }),
+    })
+}
+
+/// Encode a `FileReceiveChunk` event as a chunk frame.
+pub fn encode(event: &Event) -> Option<OwnedMessage> {
+    let (friend, file_number, position, data) = match event {
+        Event::FileReceiveChunk { friend, file_number, position, data } =>
+            (*friend, *file_number, *position, data),
+        _ => return None,
+    };
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + data.len());
+    frame.push(CHUNK_FRAME);
+    frame.extend_from_slice(&friend.to_be_bytes());
+    frame.extend_from_slice(&file_number.to_be_bytes());
+    frame.extend_from_slice(&position.to_be_bytes());
+    frame.extend_from_slice(data);
+
+    Some(OwnedMessage::Binary(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_round_trip() {
+        let event = Event::FileReceiveChunk {
+            friend: 3,
+            file_number: 0x0102_0304,
+            position: 1 << 40,
+            data: vec![1, 0, 255],
+        };
+        let frame = match encode(&event) {
+            Some(OwnedMessage::Binary(frame)) => frame,
+            _ => panic!("FileReceiveChunk is not encoded as a binary frame"),
+        };
+
+        assert!(is_chunk_frame(&frame));
+        assert_eq!(frame.len(), HEADER_LEN + 3);
+
+        let envelope = decode(&frame).ok().unwrap();
+        assert_eq!(envelope.id, Some(serde_json::json!({
+            "friend": 3,
+            "file_number": 0x0102_0304,
+            "position": 1u64 << 40,
+        })));
+
+        match envelope.request {
+            ClientRequest::Tox(Request::SendFileChunk { friend, file_number, position, data }) => {
+                assert_eq!((friend, file_number, position), (3, 0x0102_0304, 1 << 40));
+                assert_eq!(data, vec![1, 0, 255]);
+            },
+            _ => panic!("chunk frame is not decoded as SendFileChunk"),
+        }
+    }
+
+    #[test]
+    fn empty_chunks_round_trip() {
+        let event = Event::FileReceiveChunk {
+            friend: 0,
+            file_number: 0,
+            position: 7,
+            data: Vec::new(),
+        };
+
+        match encode(&event) {
+            Some(OwnedMessage::Binary(frame)) => {
+                assert_eq!(frame.len(), HEADER_LEN);
+                assert!(decode(&frame).is_ok());
+            },
+            _ => panic!("FileReceiveChunk is not encoded as a binary frame"),
+        }
+    }
+
+    #[test]
+    fn short_frames_are_refused() {
+        assert!(decode(&[CHUNK_FRAME]).is_err());
+        assert!(decode(&[CHUNK_FRAME; HEADER_LEN - 1]).is_err());
+        assert!(encode(&Event::FriendTyping { friend: 0, is_typing: true }).is_none());
+    }
+}
@@ -0,0 +1,218 @@
+use serde::Deserialize;
+
+use crate::logging::LogFormat;
+use crate::permission::Policy;
+use crate::profile::ProfileStore;
+use crate::queue::SlowConsumerPolicy;
+
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:2794";
+const DEFAULT_PROFILES_DIR: &str = "profiles";
+
+/// A profile that is started at boot and kept online without a client.
+#[derive(Clone, Deserialize)]
+pub struct ProfileConfig {
+    pub secret_key: String,
+}
+
+/// A token that gives a client restricted access to a profile.
+#[derive(Clone, Deserialize)]
+pub struct AccessConfig {
+    pub token: String,
+    pub secret_key: String,
+    #[serde(default)]
+    pub policy: Policy,
+}
+
+/// Caps on the connections a server accepts.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Running sessions of all clients together, i.e. tox instances.
+    pub max_sessions: usize,
+    /// Running sessions started from a single remote address.
+    pub max_sessions_per_ip: usize,
+    /// Connection attempts from a single remote address per minute.
+    pub max_connections_per_minute: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_sessions: 64,
+            max_sessions_per_ip: 8,
+            max_connections_per_minute: 30,
+        }
+    }
+}
+
+/// The queue of answers waiting to be sent to a client.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct AnswerQueueConfig {
+    /// Answers queued before the client counts as slow.
+    pub capacity: usize,
+    /// Answers queued at most. A client reaching it is disconnected whatever
+    /// the policy.
+    pub hard_limit: usize,
+    pub slow_consumer: SlowConsumerPolicy,
+}
+
+impl Default for AnswerQueueConfig {
+    fn default() -> Self {
+        AnswerQueueConfig {
+            capacity: 1024,
+            hard_limit: 4096,
+            slow_consumer: SlowConsumerPolicy::default(),
+        }
+    }
+}
+
+/// Pings that detect clients which are gone without closing the connection.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct KeepaliveConfig {
+    /// Seconds between pings, `0` disables them.
+    pub ping_interval: u64,
+    /// Seconds a client may take to answer a ping before the connection is
+    /// closed.
+    pub pong_timeout: u64,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            ping_interval: 30,
+            pong_timeout: 10,
+        }
+    }
+}
+
+/// What the server logs and how.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Lowest level logged, or a filter such as `info,ws_tox=debug`.
+    pub level: String,
+    pub format: LogFormat,
+    /// Whether secrets and message text are left out of the log.
+    pub redact: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_owned(),
+            format: LogFormat::default(),
+            redact: true,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Address the WebSocket server listens on.
+    pub address: String,
+    /// Headless profiles which stay online for the whole server lifetime.
+    /// They must be stored profiles, so they are saved.
+    pub profiles: Vec<ProfileConfig>,
+    /// Directory where profiles are stored.
+    pub profiles_dir: String,
+    /// Token that authorizes the profile management API. The API is
+    /// disabled when no token is set.
+    pub admin_token: Option<String>,
+    /// Tokens a client can connect with instead of the secret key.
+    pub access_tokens: Vec<AccessConfig>,
+    pub limits: LimitsConfig,
+    pub answer_queue: AnswerQueueConfig,
+    pub keepalive: KeepaliveConfig,
+    pub log: LogConfig,
+    /// Seconds a session may stay without clients before it is stopped.
+    pub idle_ttl: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: DEFAULT_ADDRESS.to_owned(),
+            profiles: Vec::new(),
+            profiles_dir: DEFAULT_PROFILES_DIR.to_owned(),
+            admin_token: None,
+            access_tokens: Vec::new(),
+            limits: LimitsConfig::default(),
+            answer_queue: AnswerQueueConfig::default(),
+            keepalive: KeepaliveConfig::default(),
+            log: LogConfig::default(),
+            idle_ttl: 600,
+        }
+    }
+}
+
+impl Config {
+    /// Load the config from the JSON file given as the first argument,
+    /// or use the defaults when no file is given.
+    pub fn load() -> Result<Config, IoError> {
+        let path = match std::env::args().nth(1) {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+
+        let file = std::fs::File::open(&path)?;
+        let config: Config = serde_json::from_reader(file)
+            .map_err(|e| e.to_string())
+            .and_then(|config: Config| config.check().map(|()| config))
+            .map_err(|e| IoError::new(IoErrorKind::InvalidData,
+                format!("bad config {}: {}", path, e)
+            ))?;
+
+        Ok(config)
+    }
+
+    /// Find what serde cannot, like secret keys that are no keys.
+    fn check(&self) -> Result<(), String> {
+        let store = ProfileStore::new(&self.profiles_dir);
+
+        for (i, profile) in self.profiles.iter().enumerate() {
+            if profile.secret_key.parse::<rstox::core::SecretKey>().is_err() {
+                return Err(format!("profiles[{}]: invalid secret key", i))
+            }
+            if store.find(&profile.secret_key).is_none() {
+                let dir = &self.profiles_dir;
+
+                return Err(format!("profiles[{}]: not a profile stored in {}", i, dir))
+            }
+        }
+
+        // a token with a bad key would open an anonymous session instead
+        for (i, access) in self.access_tokens.iter().enumerate() {
+            if access.secret_key.parse::<rstox::core::SecretKey>().is_err() {
+                return Err(format!("access_tokens[{}]: invalid secret key", i))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_tokens_need_valid_keys() {
+        let access = |secret_key: &str| AccessConfig {
+            token: "token".to_owned(),
+            secret_key: secret_key.to_owned(),
+            policy: Policy::default(),
+        };
+        let mut config = Config::default();
+
+        config.access_tokens = vec![access(&"0".repeat(64)), access("zz")];
+        assert_eq!(config.check(), Err("access_tokens[1]: invalid secret key".to_owned()));
+
+        config.access_tokens.pop();
+        assert_eq!(config.check(), Ok(()));
+    }
+}
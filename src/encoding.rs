@@ -0,0 +1,437 @@
+use serde::Serialize;
+use schemars::JsonSchema;
+use websocket::{CloseData, OwnedMessage};
+
+use std::convert::TryFrom;
+
+use crate::chunk;
+use crate::ext::{RequestEnvelope, ResponseEnvelope, ServerEvent, ServerResponse};
+use crate::protocol::Event;
+use crate::tox::Answer;
+
+/// Payload size of a data frame.
+pub fn frame_len(message: &OwnedMessage) -> usize {
+    match message {
+        OwnedMessage::Text(t) => t.len(),
+        OwnedMessage::Binary(b) => b.len(),
+        _ => 0,
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum AnyEvent<'a> {
+    Tox(#[schemars(with = "crate::mirror::Event")] &'a Event),
+    Server(&'a ServerEvent),
+}
+
+/// An answer that says whether it is a response or an event, e.g.
+/// `{"type": "event", "event": "FriendMessage", ...}`.
+#[derive(Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TaggedAnswer<'a> {
+    Response(&'a ResponseEnvelope),
+    Event(AnyEvent<'a>),
+}
+
+impl<'a> From<&'a Answer> for TaggedAnswer<'a> {
+    fn from(answer: &'a Answer) -> Self {
+        match answer {
+            Answer::Response(response) => TaggedAnswer::Response(response),
+            Answer::Event(event) => TaggedAnswer::Event(AnyEvent::Tox(event)),
+            Answer::ServerEvent(event) => TaggedAnswer::Event(AnyEvent::Server(event)),
+        }
+    }
+}
+
+/// Containers nested deeper than this in a MessagePack frame are refused
+/// before decoding, since the decoder recurses without a limit.
+const MAX_MSGPACK_DEPTH: usize = 32;
+
+/// Check that the MessagePack value at the start of `data` nests at most
+/// `max_depth` arrays and maps. The scan keeps counters instead of
+/// recursing. Truncated data passes, the decoder reports it.
+fn check_depth(data: &[u8], max_depth: usize) -> Result<(), String> {
+    fn read_len(data: &[u8], at: usize, size: usize) -> Option<u64> {
+        let bytes = data.get(at..at + size)?;
+
+        Some(bytes.iter().fold(0, |len, b| len << 8 | u64::from(*b)))
+    }
+
+    // values still to read in each open container, the outermost first
+    let mut remaining = vec![1u64];
+    let mut at = 0;
+
+    while let Some(left) = remaining.last_mut() {
+        if *left == 0 {
+            remaining.pop();
+            continue
+        }
+        *left -= 1;
+
+        let marker = match data.get(at) {
+            Some(marker) => *marker,
+            None => return Ok(()),
+        };
+        at += 1;
+
+        // bytes to skip and values contained
+        let (skip, values) = match marker {
+            0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => (0, 0),
+            0x80..=0x8f => (0, u64::from(marker & 0x0f) * 2),
+            0x90..=0x9f => (0, u64::from(marker & 0x0f)),
+            0xa0..=0xbf => (u64::from(marker & 0x1f), 0),
+            0xc4 | 0xd9 => (1 + read_len(data, at, 1).unwrap_or(0), 0),
+            0xc5 | 0xda => (2 + read_len(data, at, 2).unwrap_or(0), 0),
+            0xc6 | 0xdb => (4 + read_len(data, at, 4).unwrap_or(0), 0),
+            0xc7 => (2 + read_len(data, at, 1).unwrap_or(0), 0),
+            0xc8 => (3 + read_len(data, at, 2).unwrap_or(0), 0),
+            0xc9 => (5 + read_len(data, at, 4).unwrap_or(0), 0),
+            0xcc | 0xd0 => (1, 0),
+            0xcd | 0xd1 => (2, 0),
+            0xca | 0xce | 0xd2 => (4, 0),
+            0xcb | 0xcf | 0xd3 => (8, 0),
+            0xd4 => (2, 0),
+            0xd5 => (3, 0),
+            0xd6 => (5, 0),
+            0xd7 => (9, 0),
+            0xd8 => (17, 0),
+            0xdc => (2, read_len(data, at, 2).unwrap_or(0)),
+            0xdd => (4, read_len(data, at, 4).unwrap_or(0)),
+            0xde => (2, read_len(data, at, 2).unwrap_or(0) * 2),
+            0xdf => (4, read_len(data, at, 4).unwrap_or(0) * 2),
+            // 0xc1 is never used, the decoder refuses it
+            _ => return Ok(()),
+        };
+
+        at = match usize::try_from(skip).ok().and_then(|skip| at.checked_add(skip)) {
+            Some(next) if next <= data.len() => next,
+            _ => return Ok(()),
+        };
+
+        if values > 0 {
+            if remaining.len() > max_depth {
+                return Err(format!("values nested deeper than {} levels", max_depth))
+            }
+            remaining.push(values);
+        }
+    }
+
+    Ok(())
+}
+
+/// Convert a MessagePack value to JSON, so requests in both formats are
+/// decoded by the same code. Binary data becomes an array of bytes, the
+/// only form of bytes that survives the buffering of untagged enums.
+fn to_json(value: rmpv::Value) -> Result<serde_json::Value, String> {
+    use rmpv::Value as V;
+    use serde_json::Value as Json;
+
+    let float = |f: f64| serde_json::Number::from_f64(f)
+        .map(Json::Number)
+        .ok_or_else(|| format!("unsupported float {}", f));
+
+    let json = match value {
+        V::Nil => Json::Null,
+        V::Boolean(b) => Json::Bool(b),
+        V::Integer(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => Json::from(n),
+            (None, Some(n)) => Json::from(n),
+            (None, None) => return Err(format!("unsupported integer {}", n)),
+        },
+        V::F32(f) => float(f64::from(f))?,
+        V::F64(f) => float(f)?,
+        V::String(s) => match s.into_str() {
+            Some(s) => Json::String(s),
+            None => return Err("string is not valid UTF-8".to_owned()),
+        },
+        V::Binary(bytes) => Json::Array(bytes.into_iter().map(Json::from).collect()),
+        V::Array(values) => Json::Array(values.into_iter()
+            .map(to_json)
+            .collect::<Result<_, _>>()?),
+        V::Map(entries) => {
+            let mut map = serde_json::Map::with_capacity(entries.len());
+
+            for (key, value) in entries {
+                let key = match key {
+                    V::String(key) => key.into_str()
+                        .ok_or_else(|| "map key is not valid UTF-8".to_owned())?,
+                    _ => return Err("map key is not a string".to_owned()),
+                };
+                map.insert(key, to_json(value)?);
+            }
+
+            Json::Object(map)
+        },
+        V::Ext(..) => return Err("extension types are not supported".to_owned()),
+    };
+
+    Ok(json)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// JSON in text frames.
+    Json,
+    /// MessagePack in binary frames.
+    MsgPack,
+}
+
+/// Wire format of a connection, negotiated with `Sec-WebSocket-Protocol`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Encoding {
+    pub format: Format,
+    /// Whether answers are sent as `TaggedAnswer` instead of the legacy
+    /// untagged `Answer`.
+    pub is_tagged: bool,
+}
+
+/// Supported subprotocols.
+const PROTOCOLS: &[(&str, Encoding)] = &[
+    ("tox.json", Encoding { format: Format::Json, is_tagged: false }),
+    ("tox.msgpack", Encoding { format: Format::MsgPack, is_tagged: false }),
+    ("tox.tagged.json", Encoding { format: Format::Json, is_tagged: true }),
+    ("tox.tagged.msgpack", Encoding { format: Format::MsgPack, is_tagged: true }),
+];
+
+impl Encoding {
+    /// Used when the client asks for no subprotocol.
+    pub const LEGACY: Encoding = Encoding { format: Format::Json, is_tagged: false };
+
+    /// Names of the supported subprotocols.
+    pub fn protocols() -> Vec<String> {
+        PROTOCOLS.iter().map(|(name, _)| name.to_string()).collect()
+    }
+
+    /// The subprotocol name of the encoding.
+    pub fn protocol(self) -> &'static str {
+        PROTOCOLS.iter()
+            .find(|(_, encoding)| *encoding == self)
+            .map(|(name, _)| *name)
+            .unwrap_or("tox.json")
+    }
+
+    fn from_protocol(protocol: &str) -> Option<Encoding> {
+        PROTOCOLS.iter()
+            .find(|(name, _)| *name == protocol)
+            .map(|(_, encoding)| *encoding)
+    }
+
+    /// Pick the first subprotocol offered by the client that the server
+    /// supports.
+    pub fn negotiate(protocols: &[String]) -> Option<Encoding> {
+        protocols.iter()
+            .filter_map(|p| Encoding::from_protocol(p))
+            .next()
+    }
+
+    /// Decode a request from a data frame. Control frames give `None`,
+    /// frames that cannot be decoded give the error to answer with.
+    pub fn decode(self, message: OwnedMessage) -> Option<Result<RequestEnvelope, ResponseEnvelope>> {
+        let result = match (self.format, message) {
+            (_, OwnedMessage::Binary(ref b)) if chunk::is_chunk_frame(b) =>
+                chunk::decode(b),
+            (Format::Json, OwnedMessage::Text(t)) =>
+                serde_json::from_str(&t)
+                    .map_err(|e| ResponseEnvelope::malformed(&t, e)),
+            (Format::MsgPack, OwnedMessage::Binary(b)) =>
+                check_depth(&b, MAX_MSGPACK_DEPTH)
+                    .and_then(|()| rmpv::decode::read_value(&mut &b[..])
+                        .map_err(|e| format!("{}", e)))
+                    .and_then(to_json)
+                    .map_err(ResponseEnvelope::protocol_error)
+                    .and_then(|value| serde_json::from_value(value.clone())
+                        .map_err(|e| ResponseEnvelope::invalid(value, e))),
+            (_, OwnedMessage::Text(_)) =>
+                Err(ResponseEnvelope::unsupported_frame("text")),
+            (_, OwnedMessage::Binary(_)) =>
+                Err(ResponseEnvelope::unsupported_frame("binary")),
+            _ => return None,
+        };
+
+        Some(result)
+    }
+
+    fn serialize<T: Serialize>(self, value: &T) -> Result<OwnedMessage, String> {
+        match self.format {
+            Format::Json =>
+                serde_json::to_string(value)
+                    .map(OwnedMessage::Text)
+                    .map_err(|e| format!("{}", e)),
+            // structs are written as maps, so the tags of the enums survive
+            Format::MsgPack =>
+                rmp_serde::to_vec_named(value)
+                    .map(OwnedMessage::Binary)
+                    .map_err(|e| format!("{}", e)),
+        }
+    }
+
+    fn try_encode(self, answer: &Answer) -> Result<OwnedMessage, String> {
+        if self.is_tagged {
+            self.serialize(&TaggedAnswer::from(answer))
+        }
+        else {
+            self.serialize(answer)
+        }
+    }
+
+    /// Encode the answer. An answer that cannot be encoded is replaced with
+    /// an `UnexpectedError`, or a close frame if even that fails.
+    pub fn encode(self, answer: &Answer) -> OwnedMessage {
+        let error = match self.try_encode(answer) {
+            Ok(message) => return message,
+            Err(error) => error,
+        };
+
+        tracing::error!(%error, "failed to encode an answer");
+
+        let id = match answer {
+            Answer::Response(response) => response.id.clone(),
+            _ => None,
+        };
+        let fallback = Answer::Response(ResponseEnvelope {
+            id,
+            response: ServerResponse::UnexpectedError { error }.into(),
+        });
+
+        self.try_encode(&fallback).unwrap_or_else(|_| {
+            let reason = "failed to encode an answer".to_owned();
+
+            OwnedMessage::Close(Some(CloseData::new(crate::CLOSE_INTERNAL_ERROR, reason)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext::ClientRequest;
+    use crate::protocol::Request;
+
+    const MSGPACK: Encoding = Encoding { format: Format::MsgPack, is_tagged: false };
+
+    fn decode_msgpack(data: Vec<u8>) -> RequestEnvelope {
+        match MSGPACK.decode(OwnedMessage::Binary(data)) {
+            Some(Ok(envelope)) => envelope,
+            _ => panic!("MessagePack request is not decoded"),
+        }
+    }
+
+    #[test]
+    fn responses_are_encoded_as_msgpack() {
+        let with_id = Answer::Response(ResponseEnvelope {
+            id: Some(serde_json::json!(7)),
+            response: ServerResponse::ConferenceNotFoundError.into(),
+        });
+        let without_id = Answer::Response(ResponseEnvelope::protocol_error("bad".to_owned()));
+
+        for encoding in &[MSGPACK, Encoding { format: Format::MsgPack, is_tagged: true }] {
+            for answer in &[&with_id, &without_id] {
+                let data = match encoding.try_encode(answer) {
+                    Ok(OwnedMessage::Binary(data)) => data,
+                    other => panic!("answer is not encoded as a binary frame: {:?}", other.err()),
+                };
+                let value: serde_json::Value = rmp_serde::from_slice(&data).unwrap();
+
+                assert_eq!(value["id"], serde_json::to_value(answer).unwrap()["id"]);
+                assert!(value["response"].is_string());
+            }
+        }
+    }
+
+    #[test]
+    fn file_chunks_round_trip_as_msgpack() {
+        let request = RequestEnvelope {
+            id: Some(serde_json::json!("chunk")),
+            request: ClientRequest::Tox(Request::SendFileChunk {
+                friend: 1,
+                file_number: 2,
+                position: 3,
+                data: vec![0, 1, 0xc4, 0xff],
+            }),
+        };
+        let envelope = decode_msgpack(rmp_serde::to_vec_named(&request).unwrap());
+
+        assert_eq!(envelope.id, Some(serde_json::json!("chunk")));
+        match envelope.request {
+            ClientRequest::Tox(Request::SendFileChunk { friend, file_number, position, data }) => {
+                assert_eq!((friend, file_number, position), (1, 2, 3));
+                assert_eq!(data, vec![0, 1, 0xc4, 0xff]);
+            },
+            _ => panic!("SendFileChunk is not decoded as SendFileChunk"),
+        }
+    }
+
+    #[test]
+    fn binary_data_is_decoded_as_bytes() {
+        use rmpv::Value;
+
+        let request = Value::Map(vec![
+            (Value::from("id"), Value::from(1)),
+            (Value::from("request"), Value::from("SendFileChunk")),
+            (Value::from("friend"), Value::from(1)),
+            (Value::from("file_number"), Value::from(2)),
+            (Value::from("position"), Value::from(3)),
+            (Value::from("data"), Value::Binary(vec![0, 0xff])),
+        ]);
+        let mut data = Vec::new();
+        rmpv::encode::write_value(&mut data, &request).unwrap();
+
+        match decode_msgpack(data).request {
+            ClientRequest::Tox(Request::SendFileChunk { data, .. }) =>
+                assert_eq!(data, vec![0, 0xff]),
+            _ => panic!("SendFileChunk is not decoded as SendFileChunk"),
+        }
+    }
+
+    #[test]
+    fn unsupported_values_are_refused() {
+        let refused = [
+            // a map with an integer key
+            vec![0x81, 0x01, 0xc0],
+            // an ext value
+            vec![0xd4, 0x01, 0x00],
+            // a string that is not UTF-8
+            vec![0xa1, 0xff],
+        ];
+
+        for data in refused.iter() {
+            let value = rmpv::decode::read_value(&mut &data[..]).unwrap();
+
+            assert!(to_json(value).is_err(), "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn requests_are_not_too_deep() {
+        let batch = serde_json::json!({
+            "request": "Batch",
+            "requests": [{"request": "SetInfo", "nospam": "0", "name": "", "status": "None",
+                "status_message": "", "friends": ["0"]}],
+        });
+        let data = rmp_serde::to_vec_named(&batch).unwrap();
+
+        assert!(check_depth(&data, MAX_MSGPACK_DEPTH).is_ok());
+    }
+
+    #[test]
+    fn deep_nesting_is_refused() {
+        // arrays of one array, a million levels deep
+        let data = vec![0x91; 1_000_000];
+
+        assert!(check_depth(&data, MAX_MSGPACK_DEPTH).is_err());
+        assert!(Encoding { format: Format::MsgPack, is_tagged: false }
+            .decode(OwnedMessage::Binary(data))
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn depth_counts_maps_and_long_headers() {
+        // a map16 holding an array32 holding nil
+        let data = [0xde, 0, 1, 0xa1, b'a', 0xdd, 0, 0, 0, 1, 0xc0];
+
+        assert!(check_depth(&data, 2).is_ok());
+        assert!(check_depth(&data, 1).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+//! Requests and responses implemented by this server on top of
+//! `ws-tox-protocol`.
+
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+use crate::protocol::{ConferenceInfo, ConnectionStatus, Request, Response};
+use crate::snapshot::{FileTransfer, FriendRequest};
+use crate::subscription::Filter;
+
+/// An id chosen by the client to match a response to its request.
+pub type RequestId = serde_json::Value;
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "request")]
+pub enum ServerRequest {
+    /// State the protocol version the client speaks. A client setting
+    /// `binary_chunks` receives file chunks in binary chunk frames, one
+    /// setting `split_messages` may send messages of any length.
+    Hello {
+        protocol_version: u32,
+        #[serde(default)]
+        binary_chunks: bool,
+        #[serde(default)]
+        split_messages: bool,
+    },
+    /// Report the resource usage of the session.
+    SessionStatus,
+    /// Run the requests in order, answering with all responses at once.
+    Batch { requests: Vec<ClientRequest> },
+    /// Receive the kinds of events, or all events if `events` is empty.
+    Subscribe {
+        #[serde(default)]
+        events: Vec<String>,
+        #[serde(flatten)]
+        filter: Filter,
+    },
+    /// Stop receiving the kinds of events, or all events if `events` is
+    /// empty.
+    Unsubscribe {
+        #[serde(default)]
+        events: Vec<String>,
+    },
+    /// Report the length limits of the fields of requests.
+    GetLimits,
+    /// Report the whole state of the session at once.
+    Snapshot,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "response")]
+pub enum ServerResponse {
+    /// The server speaks the protocol version of the client.
+    Hello { protocol_version: u32 },
+    /// The server does not speak the protocol version of the client.
+    IncompatibleProtocolError {
+        min_protocol_version: u32,
+        protocol_version: u32,
+    },
+    SessionStatus {
+        /// Seconds since the session started.
+        uptime: u64,
+        /// Seconds since the last client activity.
+        idle: u64,
+        /// Number of attached clients.
+        clients: usize,
+        bytes_received: usize,
+        bytes_sent: usize,
+        /// Answers dropped because the client read them too slowly.
+        answers_dropped: usize,
+    },
+    /// Responses to the requests of a batch, in the same order.
+    Batch { responses: Vec<ClientResponse> },
+    /// The client is not allowed to issue the request.
+    PermissionDenied { request: String },
+    /// The request is part of `ws-tox-protocol` but not implemented by this
+    /// server.
+    UnsupportedRequest { request: String },
+    /// A field of the request could not be parsed.
+    InvalidFieldError { field: String },
+    /// A field of the request must not be empty.
+    EmptyFieldError { field: String },
+    /// A field of the request is longer than `max_length` bytes.
+    FieldTooLongError { field: String, max_length: usize },
+    /// A long friend message was sent as several messages.
+    SplitMessageSent { message_ids: Vec<u32> },
+    /// Sending a part of a long friend message failed after the parts in
+    /// `message_ids` were sent. `error` is the response to the failed part.
+    SplitMessageError { message_ids: Vec<u32>, error: Box<ClientResponse> },
+    /// Sending a part of a long conference message failed after
+    /// `parts_sent` parts were sent. `error` is the response to the failed
+    /// part.
+    SplitConferenceMessageError { parts_sent: usize, error: Box<ClientResponse> },
+    /// The state of the session, e.g. for a client that just connected.
+    Snapshot {
+        /// The `Info` response with the own profile and the friends.
+        #[schemars(with = "crate::mirror::Response")]
+        info: Response,
+        #[schemars(with = "crate::mirror::ConnectionStatus")]
+        connection_status: ConnectionStatus,
+        #[schemars(with = "Vec<crate::mirror::ConferenceInfo>")]
+        conferences: Vec<ConferenceInfo>,
+        friend_requests: Vec<FriendRequest>,
+        file_transfers: Vec<FileTransfer>,
+    },
+    /// Length limits in bytes of the fields of requests.
+    Limits {
+        max_name_length: usize,
+        max_status_message_length: usize,
+        max_friend_request_length: usize,
+        max_message_length: usize,
+        max_conference_title_length: usize,
+        max_file_name_length: usize,
+        max_file_chunk_length: usize,
+    },
+    /// A frame could not be parsed as a request. The location is given for
+    /// text frames with a syntax error.
+    ProtocolError {
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        line: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        column: Option<usize>,
+    },
+    /// Setting a field of the own profile failed.
+    SetInfoError { field: String, error: SetInfoError },
+    /// There is no conference with the given number.
+    ConferenceNotFoundError,
+    /// Tox failed in a way the protocol has no error for.
+    UnexpectedError { error: String },
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub enum SetInfoError {
+    Null,
+    TooLong,
+}
+
+impl From<rstox::core::errors::SetInfoError> for SetInfoError {
+    fn from(error: rstox::core::errors::SetInfoError) -> Self {
+        use rstox::core::errors::SetInfoError as E;
+
+        match error {
+            E::TooLong => SetInfoError::TooLong,
+            _ => SetInfoError::Null,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "event")]
+pub enum ServerEvent {
+    /// Sent first on every connection.
+    Hello {
+        protocol_version: u32,
+        min_protocol_version: u32,
+        server: String,
+        version: String,
+        /// Supported `Sec-WebSocket-Protocol` values.
+        encodings: Vec<String>,
+        requests: Vec<String>,
+        events: Vec<String>,
+    },
+    /// The tox instance of the session could not be started. The server
+    /// closes the connection right after it.
+    StartError { error: String },
+    /// The friend read all parts of a split message.
+    SplitMessageRead { friend: u32, message_ids: Vec<u32> },
+}
+
+/// Anything a client can send to a session.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ClientRequest {
+    Server(ServerRequest),
+    Tox(#[schemars(with = "crate::mirror::Request")] Request),
+}
+
+/// A request with an optional id, e.g. `{"id": 1, "request": "GetName"}`.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct RequestEnvelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    #[serde(flatten)]
+    pub request: ClientRequest,
+}
+
+/// Anything a session can respond with.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ClientResponse {
+    Server(ServerResponse),
+    Tox(#[schemars(with = "crate::mirror::Response")] Response),
+}
+
+impl From<Response> for ClientResponse {
+    fn from(response: Response) -> Self {
+        ClientResponse::Tox(response)
+    }
+}
+
+impl From<ServerResponse> for ClientResponse {
+    fn from(response: ServerResponse) -> Self {
+        ClientResponse::Server(response)
+    }
+}
+
+/// A response carrying the id of its request, if the request had one.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ResponseEnvelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    #[serde(flatten)]
+    pub response: ClientResponse,
+}
+
+impl ResponseEnvelope {
+    /// The error for a text frame that is not a valid request.
+    pub fn malformed(text: &str, error: serde_json::Error) -> Self {
+        let value: serde_json::Value = match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(_) => return ResponseEnvelope {
+                id: None,
+                response: ServerResponse::ProtocolError {
+                    message: format!("{}", error),
+                    line: Some(error.line()),
+                    column: Some(error.column()),
+                }.into(),
+            },
+        };
+
+        Self::invalid(value, error)
+    }
+
+    /// The error for a decoded value that is not a valid request.
+    pub fn invalid(value: serde_json::Value, error: serde_json::Error) -> Self {
+        let id = value.get("id").cloned();
+
+        // an untagged enum only says that no variant matched, so ask the
+        // enum the request names for a more specific message
+        let is_server_request = value.get("request")
+            .and_then(|name| name.as_str())
+            .map_or(false, |name| crate::hello::server_requests().iter().any(|n| n == name));
+        let result = if is_server_request {
+            serde_json::from_value::<ServerRequest>(value).map(drop)
+        }
+        else {
+            serde_json::from_value::<Request>(value).map(drop)
+        };
+        let message = match result {
+            Err(e) => format!("{}", e),
+            Ok(()) => format!("{}", error),
+        };
+
+        ResponseEnvelope {
+            id,
+            response: ServerResponse::ProtocolError {
+                message,
+                line: None,
+                column: None,
+            }.into(),
+        }
+    }
+
+    /// The error for a frame that cannot be decoded.
+    pub fn protocol_error(message: String) -> Self {
+        ResponseEnvelope {
+            id: None,
+            response: ServerResponse::ProtocolError {
+                message,
+                line: None,
+                column: None,
+            }.into(),
+        }
+    }
+
+    /// The error for a frame of a kind the server does not accept.
+    pub fn unsupported_frame(kind: &str) -> Self {
+        Self::protocol_error(format!("unsupported {} frame", kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_message(text: &str) -> String {
+        let error = serde_json::from_str::<RequestEnvelope>(text).err().unwrap();
+
+        match ResponseEnvelope::malformed(text, error).response {
+            ClientResponse::Server(ServerResponse::ProtocolError { message, .. }) => message,
+            _ => panic!("malformed request is not answered with ProtocolError"),
+        }
+    }
+
+    #[test]
+    fn server_requests_are_explained() {
+        let message = error_message(r#"{"request": "Subscribe", "events": 5}"#);
+
+        assert!(message.contains("invalid type"), "{}", message);
+    }
+
+    #[test]
+    fn tox_requests_are_explained() {
+        let message = error_message(r#"{"request": "GetFriendName", "friend": "0"}"#);
+
+        assert!(message.contains("invalid type"), "{}", message);
+        assert!(error_message(r#"{"request": "Nonsense"}"#).contains("unknown variant"));
+    }
+
+    #[test]
+    fn server_request_names_are_server_requests() {
+        for name in crate::hello::server_requests() {
+            let request = serde_json::json!({ "request": name });
+
+            if let Err(e) = serde_json::from_value::<ServerRequest>(request) {
+                assert!(!format!("{}", e).contains("unknown variant"), "{}", name);
+            }
+        }
+    }
+}
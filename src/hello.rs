@@ -0,0 +1,70 @@
+use websocket::{CloseData, OwnedMessage};
+
+use crate::encoding::Encoding;
+use crate::ext::{ServerEvent, ServerRequest, ServerResponse};
+use crate::variant;
+
+/// Version of the protocol spoken by this server.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version this server still speaks.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// WebSocket close code for a client speaking an unsupported protocol.
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+/// Requests the server adds to the tox requests.
+pub fn server_requests() -> Vec<String> {
+    variant::names::<ServerRequest>("request")
+}
+
+/// Tox requests implemented by the server.
+fn tox_requests() -> Vec<String> {
+    variant::names::<crate::mirror::Request>("request")
+}
+
+/// Events the server sends besides the tox events.
+fn server_events() -> Vec<String> {
+    variant::names::<ServerEvent>("event")
+}
+
+/// Events of tox, the ones a client can subscribe to.
+pub fn tox_events() -> Vec<String> {
+    variant::names::<crate::mirror::Event>("event")
+}
+
+/// The event sent to every client right after the handshake.
+pub fn hello() -> ServerEvent {
+    ServerEvent::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: MIN_PROTOCOL_VERSION,
+        server: env!("CARGO_PKG_NAME").to_owned(),
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        encodings: Encoding::protocols(),
+        requests: [server_requests(), tox_requests()].concat(),
+        events: [server_events(), tox_events()].concat(),
+    }
+}
+
+/// Answer the `Hello` request of a client speaking `protocol_version`.
+/// An incompatible client gets the error and should be disconnected.
+pub fn negotiate(protocol_version: u32) -> Result<ServerResponse, ServerResponse> {
+    if protocol_version >= MIN_PROTOCOL_VERSION && protocol_version <= PROTOCOL_VERSION {
+        Ok(ServerResponse::Hello { protocol_version })
+    }
+    else {
+        Err(ServerResponse::IncompatibleProtocolError {
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            protocol_version: PROTOCOL_VERSION,
+        })
+    }
+}
+
+/// The close frame for an incompatible client.
+pub fn incompatible() -> OwnedMessage {
+    let reason = format!(
+        "protocol versions {} to {} are supported",
+        MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+    );
+
+    OwnedMessage::Close(Some(CloseData::new(CLOSE_PROTOCOL_ERROR, reason)))
+}
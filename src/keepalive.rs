@@ -0,0 +1,80 @@
+//! Pings that detect connections whose peer is gone without closing them.
+
+use futures::{Future, Stream};
+use tokio::timer::{Delay, Interval};
+use websocket::OwnedMessage;
+
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::KeepaliveConfig;
+
+/// When a connection last received a frame from its peer.
+#[derive(Clone)]
+pub struct Liveness {
+    last_seen: Arc<Mutex<Instant>>,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Liveness { last_seen: Arc::new(Mutex::new(Instant::now())) }
+    }
+}
+
+impl Liveness {
+    pub fn new() -> Self {
+        Liveness::default()
+    }
+
+    /// Any frame, a pong or not, shows that the peer is alive.
+    pub fn seen(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now()
+    }
+
+    /// Pings to send to the peer. The stream fails when the peer sent
+    /// nothing within the pong timeout of a ping, which ends the
+    /// connection. A pong timeout longer than the ping interval delays the
+    /// next ping.
+    pub fn pings(&self, config: &KeepaliveConfig)
+        -> Box<dyn Stream<Item = OwnedMessage, Error = IoError> + Send>
+    {
+        if config.ping_interval == 0 {
+            return Box::new(futures::stream::empty())
+        }
+
+        let interval = Duration::from_secs(config.ping_interval);
+        let pong_timeout = Duration::from_secs(config.pong_timeout);
+        let last_seen = self.last_seen.clone();
+
+        let pings = Interval::new(Instant::now() + interval, interval)
+            .map_err(timer_error)
+            .map(move |_| {
+                let sent = Instant::now();
+                let last_seen = last_seen.clone();
+
+                // any frame after the ping answers it
+                let answered = Delay::new(sent + pong_timeout)
+                    .map_err(timer_error)
+                    .and_then(move |()| {
+                        if *last_seen.lock().unwrap() >= sent {
+                            Ok(None)
+                        }
+                        else {
+                            Err(IoError::new(IoErrorKind::TimedOut, "peer stopped answering pings"))
+                        }
+                    });
+
+                futures::stream::once(Ok(Some(OwnedMessage::Ping(Vec::new()))))
+                    .chain(answered.into_stream())
+            })
+            .flatten()
+            .filter_map(|ping| ping);
+
+        Box::new(pings)
+    }
+}
+
+fn timer_error(e: tokio::timer::Error) -> IoError {
+    IoError::new(IoErrorKind::Other, format!("keepalive timer err: {}", e))
+}
@@ -0,0 +1,208 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::LimitsConfig;
+
+/// WebSocket close code for a server that is overloaded.
+const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+/// WebSocket close code for a client that breaks the server policy.
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+#[derive(Debug)]
+pub enum Refusal {
+    TooManySessions,
+    TooManySessionsFromAddress,
+    TooManyAttempts,
+}
+
+impl Refusal {
+    pub fn close_code(&self) -> u16 {
+        match self {
+            Refusal::TooManySessions => CLOSE_TRY_AGAIN_LATER,
+            Refusal::TooManySessionsFromAddress => CLOSE_POLICY_VIOLATION,
+            Refusal::TooManyAttempts => CLOSE_POLICY_VIOLATION,
+        }
+    }
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            Refusal::TooManySessions => "server session limit reached",
+            Refusal::TooManySessionsFromAddress => "too many sessions from this address",
+            Refusal::TooManyAttempts => "too many connection attempts, try again later",
+        };
+
+        f.write_str(reason)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    sessions: usize,
+    sessions_per_ip: HashMap<IpAddr, usize>,
+    attempts: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+/// Counts connection attempts and running sessions, refusing the ones over
+/// the configured limits.
+#[derive(Clone)]
+pub struct Limiter {
+    config: LimitsConfig,
+    state: Arc<Mutex<State>>,
+}
+
+/// A session slot, held for as long as the tox instance runs. It is
+/// released when the permit is dropped.
+pub struct Permit {
+    ip: IpAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.sessions -= 1;
+
+        let is_last = match state.sessions_per_ip.get_mut(&self.ip) {
+            Some(count) => { *count -= 1; *count == 0 },
+            None => false,
+        };
+
+        if is_last {
+            state.sessions_per_ip.remove(&self.ip);
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(config: LimitsConfig) -> Self {
+        Limiter {
+            config,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Register a connection attempt from `ip`.
+    pub fn admit(&self, ip: IpAddr) -> Result<(), Refusal> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let minute = Duration::from_secs(60);
+
+        // forget the addresses with no attempts in the last minute
+        state.attempts.retain(|_, attempts| {
+            while attempts.front().map_or(false, |t| now.duration_since(*t) >= minute) {
+                attempts.pop_front();
+            }
+
+            !attempts.is_empty()
+        });
+
+        // refused attempts are not recorded, so the record of an address
+        // stays within the limit
+        let attempts = state.attempts.entry(ip).or_insert_with(VecDeque::new);
+        if attempts.len() >= self.config.max_connections_per_minute {
+            return Err(Refusal::TooManyAttempts)
+        }
+        attempts.push_back(now);
+
+        Ok(())
+    }
+
+    /// Take a slot for a new session started by `ip`.
+    pub fn reserve(&self, ip: IpAddr) -> Result<Permit, Refusal> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.sessions >= self.config.max_sessions {
+            return Err(Refusal::TooManySessions)
+        }
+
+        let per_ip = state.sessions_per_ip.get(&ip).cloned().unwrap_or(0);
+        if per_ip >= self.config.max_sessions_per_ip {
+            return Err(Refusal::TooManySessionsFromAddress)
+        }
+
+        state.sessions += 1;
+        state.sessions_per_ip.insert(ip, per_ip + 1);
+
+        Ok(Permit {
+            ip,
+            state: self.state.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_sessions: usize, max_sessions_per_ip: usize, attempts: usize) -> Limiter {
+        Limiter::new(LimitsConfig {
+            max_sessions,
+            max_sessions_per_ip,
+            max_connections_per_minute: attempts,
+        })
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn attempts_are_limited_per_address() {
+        let limiter = limiter(8, 8, 2);
+
+        assert!(limiter.admit(ip(1)).is_ok());
+        assert!(limiter.admit(ip(1)).is_ok());
+
+        let refusal = limiter.admit(ip(1)).unwrap_err();
+        assert!(matches!(refusal, Refusal::TooManyAttempts));
+        assert_eq!(refusal.close_code(), 1008);
+
+        // every address has its own count
+        assert!(limiter.admit(ip(2)).is_ok());
+    }
+
+    #[test]
+    fn sessions_are_limited_per_address() {
+        let limiter = limiter(8, 1, 8);
+
+        let _permit = limiter.reserve(ip(1)).unwrap();
+
+        let refusal = limiter.reserve(ip(1)).err().unwrap();
+        assert!(matches!(refusal, Refusal::TooManySessionsFromAddress));
+        assert_eq!(refusal.close_code(), 1008);
+        assert!(limiter.reserve(ip(2)).is_ok());
+    }
+
+    #[test]
+    fn sessions_are_limited_in_total() {
+        let limiter = limiter(2, 2, 8);
+
+        let _first = limiter.reserve(ip(1)).unwrap();
+        let _second = limiter.reserve(ip(2)).unwrap();
+
+        let refusal = limiter.reserve(ip(3)).err().unwrap();
+        assert!(matches!(refusal, Refusal::TooManySessions));
+        assert_eq!(refusal.close_code(), 1013);
+    }
+
+    #[test]
+    fn dropped_permits_release_their_slot() {
+        let limiter = limiter(1, 1, 8);
+
+        let permit = limiter.reserve(ip(1)).unwrap();
+        assert!(limiter.reserve(ip(1)).is_err());
+        assert!(limiter.reserve(ip(2)).is_err());
+
+        drop(permit);
+        assert!(limiter.state.lock().unwrap().sessions_per_ip.is_empty());
+
+        let _permit = limiter.reserve(ip(2)).unwrap();
+        assert_eq!(limiter.state.lock().unwrap().sessions, 1);
+    }
+}
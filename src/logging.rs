@@ -0,0 +1,72 @@
+//! Diagnostics of the server, written to stderr.
+//!
+//! Secrets and message text are redacted unless the config turns redaction
+//! off, e.g. for debugging a local server.
+
+use serde::Deserialize;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::LogConfig;
+use crate::ext::ClientRequest;
+use crate::permission::request_name;
+
+const REDACTED: &str = "[redacted]";
+
+static REDACT: AtomicBool = AtomicBool::new(true);
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+/// Install the logger. `RUST_LOG` overrides the configured level.
+pub fn init(config: &LogConfig) {
+    use tracing_subscriber::EnvFilter;
+
+    REDACT.store(config.redact, Ordering::SeqCst);
+
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(&config.level));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    match config.format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// The text, or a placeholder when redacting.
+pub fn redact(text: &str) -> &str {
+    if REDACT.load(Ordering::Relaxed) { REDACTED } else { text }
+}
+
+/// The start of a public key or Tox ID, enough to tell sessions apart.
+pub fn key_prefix(key: &str) -> &str {
+    key.get(..8).unwrap_or(key)
+}
+
+/// A request as logged: only its name when redacting, since fields such as
+/// messages or keys may be private.
+pub fn request(request: &ClientRequest) -> String {
+    if !REDACT.load(Ordering::Relaxed) {
+        return serde_json::to_string(request).unwrap_or_default()
+    }
+
+    match request {
+        ClientRequest::Tox(request) => request_name(request),
+        ClientRequest::Server(request) => crate::variant::name(request, "request"),
+    }
+}
@@ -1,18 +1,57 @@
-use crate::tox::ToxHandle;
+use crate::config::{AccessConfig, AnswerQueueConfig, Config, KeepaliveConfig};
+use crate::encoding::{frame_len, Encoding};
+use crate::keepalive::Liveness;
+use crate::ext::{ClientRequest, RequestEnvelope, ResponseEnvelope, ServerEvent, ServerRequest};
+use crate::limits::{Limiter, Permit, Refusal};
+use crate::permission::Policy;
+use crate::profile::ProfileStore;
+use crate::session::{Attached, Sessions};
+use crate::tox::{Answer, Incoming, StartError, ToxGuard, ToxHandle};
 use crate::tox::spawn_tox;
 
 use websocket::server::InvalidConnection;
 use websocket::server::r#async::Server;
+use websocket::server::upgrade::r#async::Upgrade;
+use websocket::OwnedMessage;
 
 use futures::{future, Future, Sink, Stream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio::reactor::Handle as ReactorHandle;
 
 use ws_tox_protocol as protocol;
 
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
 use std::fmt::Debug;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 
+mod admin;
+mod chunk;
+mod config;
+mod encoding;
+mod ext;
+mod hello;
+mod keepalive;
+mod limits;
+mod logging;
+mod mirror;
+mod permission;
+mod profile;
+mod queue;
+mod schema;
+mod split;
+mod session;
+mod snapshot;
+mod subscription;
 mod tox;
+mod validate;
+mod variant;
+
+/// WebSocket close code for a server failing to start the session.
+const CLOSE_INTERNAL_ERROR: u16 = 1011;
 
 fn spawn_future<F, I, E>(f: F, desc: &'static str)
 where
@@ -20,93 +59,514 @@ where
     E: Debug,
 {
     tokio::spawn(
-        f.map_err(move |e| eprintln!("{}: '{:?}'", desc, e))
-            .map(move |_| eprintln!("{}: Finished.", desc)),
+        f.map_err(move |e| tracing::warn!(task = desc, error = ?e, "task failed"))
+            .map(move |_| tracing::debug!(task = desc, "task finished")),
     );
 }
 
+/// Complete the handshake and close the connection right away, so the client
+/// gets the reason of the refusal.
+fn refuse(
+    upgrade: Upgrade<tokio::net::TcpStream>,
+    refusal: Refusal
+) -> impl Future<Item = (), Error = websocket::WebSocketError> {
+    use websocket::CloseData;
+
+    let close = CloseData::new(refusal.close_code(), format!("{}", refusal));
+
+    upgrade
+        .accept()
+        .and_then(move |(s, _h)| s.send(OwnedMessage::Close(Some(close))))
+        .map(|_| ())
+}
+
+/// Accept the connection only to tell the client why its session could not
+/// be started.
+fn fail_start(
+    upgrade: Upgrade<tokio::net::TcpStream>,
+    encoding: Encoding,
+    error: StartError
+) -> impl Future<Item = (), Error = websocket::WebSocketError> {
+    use websocket::CloseData;
+
+    let event = encoding.encode(&Answer::ServerEvent(ServerEvent::StartError {
+        error: format!("{}", error)
+    }));
+    let close = CloseData::new(CLOSE_INTERNAL_ERROR, format!("{}", error));
+
+    upgrade
+        .accept()
+        .and_then(move |(s, _h)| s.send(event))
+        .and_then(move |s| s.send(OwnedMessage::Close(Some(close))))
+        .map(|_| ())
+}
+
+/// What a client opted into with its `Hello` request.
+#[derive(Default)]
+struct ClientOptions {
+    binary_chunks: AtomicBool,
+    split_messages: AtomicBool,
+    /// Set once the connection is being closed, no frame is read after.
+    is_closing: AtomicBool,
+}
+
+/// Decode a frame into a request for the tox thread. Frames the connection
+/// answers itself get their reply in `local_tx`, where `None` ends the
+/// connection.
+fn read_frame(
+    encoding: Encoding,
+    options: &ClientOptions,
+    local_tx: &mut UnboundedSender<Option<OwnedMessage>>,
+    message: OwnedMessage
+) -> Option<RequestEnvelope> {
+    if let OwnedMessage::Ping(data) = message {
+        drop(local_tx.try_send(Some(OwnedMessage::Pong(data))));
+        return None
+    }
+
+    let envelope = match encoding.decode(message)? {
+        Ok(envelope) => envelope,
+        Err(error) => {
+            drop(local_tx.try_send(Some(encoding.encode(&Answer::Response(error)))));
+            return None
+        },
+    };
+
+    let protocol_version = match envelope.request {
+        ClientRequest::Server(ServerRequest::Hello {
+            protocol_version,
+            binary_chunks,
+            split_messages,
+        }) => {
+            options.binary_chunks.store(binary_chunks, Ordering::SeqCst);
+            options.split_messages.store(split_messages, Ordering::SeqCst);
+            protocol_version
+        },
+        _ => return Some(envelope),
+    };
+
+    let (response, close) = match hello::negotiate(protocol_version) {
+        Ok(response) => (response, None),
+        Err(response) => (response, Some(hello::incompatible())),
+    };
+    let answer = Answer::Response(ResponseEnvelope {
+        id: envelope.id,
+        response: response.into()
+    });
+
+    drop(local_tx.try_send(Some(encoding.encode(&answer))));
+    if let Some(close) = close {
+        // nothing is read or sent after the close frame
+        options.is_closing.store(true, Ordering::SeqCst);
+        drop(local_tx.try_send(Some(close)));
+        drop(local_tx.try_send(None))
+    }
+
+    None
+}
+
+/// Start the headless profiles. Their keys are checked with the config, and
+/// one that fails to start does not keep the others from starting.
+fn spawn_profiles(
+    config: &Config,
+    store: &ProfileStore,
+    sessions: &Sessions,
+) -> impl Future<Item = (), Error = IoError> {
+    let started: Vec<_> = config.profiles.iter()
+        .filter_map(|profile| profile.secret_key.parse::<rstox::core::SecretKey>().ok())
+        .map(|secret_key| {
+            let key = format!("{}", secret_key);
+            let sessions = sessions.clone();
+            // nobody listens to the answers until a client attaches
+            let policy = Arc::new(Policy::default());
+
+            spawn_tox(Some(secret_key), store.clone(), config.answer_queue.clone(), policy)
+                .then(move |started| {
+                    match started {
+                        Ok(handle) => drop(sessions.insert(key, handle, None)),
+                        Err(e) => tracing::error!(error = %e, "failed to start a headless profile"),
+                    }
+
+                    Ok::<_, IoError>(())
+                })
+        })
+        .collect();
+
+    future::join_all(started).map(drop)
+}
+
+/// Resolves on SIGTERM, the signal service managers stop the server with.
+#[cfg(unix)]
+fn terminated() -> Box<dyn Future<Item = (), Error = IoError> + Send> {
+    use tokio_signal::unix::{Signal, SIGTERM};
+
+    Box::new(Signal::new(SIGTERM)
+        .and_then(|signals| signals.into_future().map(drop).map_err(|(e, _)| e)))
+}
+
+#[cfg(not(unix))]
+fn terminated() -> Box<dyn Future<Item = (), Error = IoError> + Send> {
+    Box::new(future::empty())
+}
+
+/// Resolves when the server is asked to stop, by Ctrl-C or SIGTERM.
+fn shutdown() -> impl Future<Item = (), Error = IoError> {
+    tokio_signal::ctrl_c()
+        .and_then(|signals| signals.into_future().map(drop).map_err(|(e, _)| e))
+        .select(terminated())
+        .map(drop)
+        .map_err(|(e, _)| e)
+}
+
+/// Find the secret key and the policy for the credential given in the URI.
+/// A secret key gives full access, an access token the configured one.
+fn resolve_credential(
+    access_tokens: &[AccessConfig],
+    credential: &str
+) -> (Option<rstox::core::SecretKey>, Policy) {
+    if let Ok(secret_key) = credential.parse() {
+        return (Some(secret_key), Policy::default())
+    }
+
+    let access = access_tokens.iter()
+        .find(|access| access.token == credential);
+
+    match access {
+        // the keys of access tokens are checked with the config
+        Some(access) => (access.secret_key.parse().ok(), access.policy.clone()),
+        None => (None, Policy::default()),
+    }
+}
+
+/// Why a client got no session.
+enum OpenError {
+    /// Starting another session would break the limits.
+    Refused(Refusal),
+    Failed(StartError),
+}
+
+impl From<Refusal> for OpenError {
+    fn from(refusal: Refusal) -> Self {
+        OpenError::Refused(refusal)
+    }
+}
+
+impl From<StartError> for OpenError {
+    fn from(error: StartError) -> Self {
+        OpenError::Failed(error)
+    }
+}
+
+/// Attach to the session of the secret key, starting it if needed. Sessions
+/// started with a secret key outlive the connection until they are reaped,
+/// the others are stopped when the returned guard is dropped. A started
+/// session takes a permit from the limiter for as long as it runs.
+fn open_session(
+    sessions: &Sessions,
+    store: &ProfileStore,
+    limiter: &Limiter,
+    ip: IpAddr,
+    queue: &AnswerQueueConfig,
+    secret_key: Option<rstox::core::SecretKey>,
+    policy: Arc<Policy>
+) -> Box<dyn Future<Item = (Attached, Option<(ToxGuard, Permit)>), Error = OpenError> + Send> {
+    let (store, queue) = (store.clone(), queue.clone());
+
+    match secret_key {
+        Some(sk) => {
+            let key = format!("{}", sk);
+
+            if let Some(attached) = sessions.attach(&key, policy.clone()) {
+                return Box::new(future::ok((attached, None)))
+            }
+
+            let sessions = sessions.clone();
+            let f = future::result(limiter.reserve(ip))
+                .from_err()
+                .and_then(move |permit| spawn_tox(Some(sk), store, queue, policy)
+                    .from_err()
+                    .map(move |handle| (sessions.insert(key, handle, Some(permit)), None)));
+
+            Box::new(f)
+        },
+        None => {
+            let f = future::result(limiter.reserve(ip))
+                .from_err()
+                .and_then(move |permit| spawn_tox(None, store, queue, policy)
+                    .from_err()
+                    .map(move |handle| {
+                        let ToxHandle { request_tx, client, answer_rx, stats, guard, .. } = handle;
+
+                        (Attached { request_tx, client, answer_rx, stats }, Some((guard, permit)))
+                    }));
+
+            Box::new(f)
+        },
+    }
+}
+
+/// Answer plain HTTP requests, which the WebSocket server reports as
+/// invalid connections. Only `GET /schema` is served.
+fn serve_http<B>(invalid: InvalidConnection<tokio::net::TcpStream, B>) {
+    let InvalidConnection { stream, parsed, error, .. } = invalid;
+    let path = parsed.map(|request| format!("{}", request.subject.1));
+
+    match (stream, path) {
+        (Some(stream), Some(ref path)) if path == "/schema" => {
+            let body = schema::to_json();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: application/schema+json\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(), body
+            );
+
+            let f = tokio::io::write_all(stream, response.into_bytes()).map(|_| ());
+            spawn_future(f, "Schema Request");
+        },
+        _ => tracing::debug!(%error, "bad client"),
+    }
+}
+
+/// Serve a client attached to a session until either side ends the
+/// connection. The guard stops a session that ends with the connection.
+fn serve_client(
+    upgrade: Upgrade<tokio::net::TcpStream>,
+    encoding: Encoding,
+    attached: Attached,
+    guard: Option<(ToxGuard, Permit)>,
+    keepalive: KeepaliveConfig
+) -> impl Future<Item = (), Error = IoError> + Send {
+    let Attached { request_tx, client, answer_rx, stats } = attached;
+
+    stats.connect();
+    let (stats_in, stats_out) = (stats.clone(), stats.clone());
+
+    upgrade
+        .accept()
+        .map_err(|e| IoError::new(IoErrorKind::Other,
+            format!("websocket accept err: {}", e)
+        ))
+        .and_then(move |(s, _h)| {
+            let (sink, stream) = s.split();
+            // replies to frames that never reach the tox thread
+            let (mut local_tx, local_rx) = unbounded_channel();
+            let options = Arc::new(ClientOptions::default());
+            let (options_in, options_out) = (options.clone(), options.clone());
+            let options_read = options.clone();
+            let liveness = Liveness::new();
+            let pings = liveness.pings(&keepalive);
+
+            let to_tox = stream
+                .take_while(move |m| Ok(
+                    !m.is_close() && !options_read.is_closing.load(Ordering::SeqCst)
+                ))
+                .filter_map(move |m| {
+                    liveness.seen();
+                    stats_in.received(frame_len(&m));
+
+                    read_frame(encoding, &options, &mut local_tx, m)
+                })
+                .map_err(|e| IoError::new(IoErrorKind::Other,
+                    format!("websocket read err: {}", e)
+                ))
+                .for_each(move |envelope: RequestEnvelope| {
+                    let incoming = Incoming {
+                        id: envelope.id,
+                        request: envelope.request,
+                        client,
+                        split_messages: options_in.split_messages.load(Ordering::SeqCst),
+                    };
+
+                    request_tx.send(incoming)
+                        .map_err(|_| IoError::new(IoErrorKind::Other, "tox_tx dropped"))
+                });
+
+            let greeting = encoding.encode(&Answer::ServerEvent(hello::hello()));
+
+            // the connection ends when the session stops sending answers,
+            // e.g. when the session stops, when the client stops answering
+            // pings or after it was refused for its protocol version
+            let answers = answer_rx
+                .map(move |r| {
+                    let chunk = match r {
+                        Answer::Event(ref e) if options_out.binary_chunks.load(Ordering::SeqCst) =>
+                            chunk::encode(e),
+                        _ => None,
+                    };
+
+                    Some(chunk.unwrap_or_else(|| encoding.encode(&r)))
+                })
+                .chain(futures::stream::once(Ok(None)))
+                .select(local_rx.map_err(|_| ()))
+                .map_err(|_| IoError::new(IoErrorKind::Other, "answer_rx dropped"))
+                .select(pings.map(Some))
+                .take_while(|m| Ok(m.is_some()))
+                .filter_map(|m| m);
+
+            let from_tox = futures::stream::once(Ok(greeting))
+                .chain(answers)
+                .inspect(move |m| stats_out.sent(frame_len(m)))
+                .forward(sink.sink_map_err(|e| IoError::new(IoErrorKind::Other,
+                    format!("websocket write err: {}", e)
+                )))
+                .map(|_| ());;
+
+            to_tox.select(from_tox)
+                .map(|_| ())
+                .map_err(|(e, _)| e)
+        })
+        .then(move |r| {
+            stats.disconnect();
+            drop(guard);
+
+            r
+        })
+}
+
 fn main() {
-    let server = Server::bind("127.0.0.1:2794", &ReactorHandle::default()).unwrap();
+    if std::env::args().nth(1).as_deref() == Some("schema") {
+        println!("{}", schema::to_json());
+        return
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        },
+    };
+    logging::init(&config.log);
+
+    let store = ProfileStore::new(&config.profiles_dir);
+    let sessions = Sessions::new();
+    let mut runtime = tokio::runtime::Builder::new().build().unwrap();
+
+    // startup finishes before the server accepts connections
+    runtime.block_on(spawn_profiles(&config, &store, &sessions)).unwrap();
+    sessions.spawn_reaper(Duration::from_secs(config.idle_ttl));
+    let stopping = sessions.clone();
+
+    let admin_token = config.admin_token.clone();
+    let limiter = Limiter::new(config.limits.clone());
+    let access_tokens = config.access_tokens.clone();
+    let answer_queue = config.answer_queue.clone();
+    let keepalive = config.keepalive.clone();
+
+    let server = Server::bind(&config.address, &ReactorHandle::default()).unwrap();
 
     let f = server
         .incoming()
-        .then(future::ok) // wrap good and bad events into future::ok
-        .filter(|event| {
+        .then(|event| {
             match event {
-                Ok(_) => true, // a good connection
-                Err(InvalidConnection { ref error, .. }) => {
-                    eprintln!("Bad client: {}", error);
-                    false // we want to save the stream if a client cannot make a valid handshake
+                Ok(connection) => Ok::<_, IoError>(Some(connection)), // a good connection
+                Err(invalid) => {
+                    serve_http(invalid);
+                    Ok(None) // we want to save the stream if a client cannot make a valid handshake
                 }
             }
         })
-        .and_then(|event| event) // unwrap good connections
-        .map_err(|_| IoError::new(IoErrorKind::Other, "invalid connection"))
+        .filter_map(|connection| connection) // unwrap good connections
         .for_each(move |(upgrade, addr)| {
-            eprintln!("Got a connection from: {}", addr);
+            tracing::info!(%addr, "connection");
+
+            if let Err(refusal) = limiter.admit(addr.ip()) {
+                tracing::warn!(%addr, %refusal, "connection refused");
+                spawn_future(refuse(upgrade, refusal), "Client Refused");
+
+                return Ok(())
+            }
 
             let uri = upgrade.uri();
-            let secret_key =
+
+            if uri.get(0..7) == Some("/admin/") {
+                let authorized = admin_token.is_some()
+                    && uri.get(7..) == admin_token.as_deref();
+
+                if authorized {
+                    let f = admin::serve(upgrade, store.clone(), sessions.clone());
+                    spawn_future(f, "Admin Status");
+                }
+                else {
+                    spawn_future(upgrade.reject(), "Admin Rejected");
+                }
+
+                return Ok(())
+            }
+
+            let (secret_key, policy) =
                 if uri.get(0..4) == Some("/ws/") {
-                    uri.get(4..).and_then(|sk| sk.parse().ok())
+                    resolve_credential(&access_tokens, &uri[4..])
                 }
-                else { None };
+                else { (None, Policy::default()) };
+            let policy = Arc::new(policy);
 
-            let ToxHandle { request_tx, answer_rx, guard } = spawn_tox(secret_key);
+            // clients asking for no subprotocol get untagged JSON in text frames
+            let encoding = Encoding::negotiate(upgrade.protocols());
+            let upgrade = match encoding {
+                Some(encoding) => upgrade.use_protocol(encoding.protocol()),
+                None => upgrade,
+            };
+            let encoding = encoding.unwrap_or(Encoding::LEGACY);
 
-            let f = upgrade
-                .accept()
-                .map_err(|e| IoError::new(IoErrorKind::Other,
-                    format!("websocket accept err: {}", e)
-                ))
-                .and_then(move |(s, _h)| {
-                    let (sink, stream) = s.split();
-
-                    let to_tox = stream
-                        .take_while(|m| Ok(!m.is_close()))
-                        .filter_map(|m| {
-                            use websocket::OwnedMessage;
-
-                            match m {
-                                OwnedMessage::Text(t) => {
-                                    serde_json::from_str(&t).ok()
-                                },
-                                _ => None,
-                            }
-                        })
-                        .map_err(|e| IoError::new(IoErrorKind::Other,
-                            format!("websocket read err: {}", e)
-                        ))
-                        .for_each(move |req: protocol::Request| {
-                            request_tx.send(req)
-                                .map_err(|_| IoError::new(IoErrorKind::Other, "tox_tx dropped"))
-                        });
-
-                    let from_tox = answer_rx
-                        .map_err(|_| IoError::new(IoErrorKind::Other, "answer_rx dropped"))
-                        .map(move |r| {
-                            let answer = serde_json::to_string(&r).unwrap();
-                            websocket::OwnedMessage::Text(answer)
-                        })
-                        .forward(sink.sink_map_err(|e| IoError::new(IoErrorKind::Other,
-                            format!("websocket write err: {}", e)
-                        )))
-                        .map(|_| ());;
+            let keepalive = keepalive.clone();
+            let f = open_session(
+                &sessions, &store, &limiter, addr.ip(), &answer_queue, secret_key, policy
+            )
+            .then(move |opened| -> Box<dyn Future<Item = (), Error = IoError> + Send> {
+                // the guard and the permit of a session that stops with the connection
+                match opened {
+                    Ok((attached, guard)) =>
+                        Box::new(serve_client(upgrade, encoding, attached, guard, keepalive)),
+                    Err(OpenError::Refused(refusal)) => {
+                        tracing::warn!(%addr, %refusal, "session refused");
 
-                    to_tox.select(from_tox)
-                        .map(|_| ())
-                        .map_err(|(e, _)| e)
-                })
-                .then(move |r| {
-                    drop(guard);
+                        Box::new(refuse(upgrade, refusal).map_err(|e| IoError::new(
+                            IoErrorKind::Other, format!("websocket refuse err: {}", e)
+                        )))
+                    },
+                    Err(OpenError::Failed(e)) => {
+                        tracing::error!(%addr, error = %e, "failed to start a session");
 
-                    r
-                });
+                        Box::new(fail_start(upgrade, encoding, e).map_err(|e| IoError::new(
+                            IoErrorKind::Other, format!("websocket fail_start err: {}", e)
+                        )))
+                    },
+                }
+            });
 
             spawn_future(f, "Client Status");
             Ok(())
         });
 
-    let mut runtime = tokio::runtime::Builder::new().build().unwrap();
-    runtime.block_on(f).unwrap();
+    runtime.block_on(f.select(shutdown()).map(drop).map_err(|(e, _)| e)).unwrap();
+
+    tracing::info!("stopping");
+    stopping.stop_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incompatible_clients_are_closed() {
+        let options = ClientOptions::default();
+        let (mut local_tx, local_rx) = unbounded_channel();
+        let hello = r#"{"request": "Hello", "protocol_version": 0}"#.to_owned();
+
+        let request = read_frame(Encoding::LEGACY, &options, &mut local_tx, OwnedMessage::Text(hello));
+        let sent: Vec<_> = local_rx.take(3).wait().map(Result::unwrap).collect();
+
+        assert!(request.is_none());
+        assert!(options.is_closing.load(Ordering::SeqCst));
+        assert!(matches!(
+            sent[0],
+            Some(OwnedMessage::Text(ref text)) if text.contains("IncompatibleProtocolError")
+        ));
+        assert!(matches!(sent[1], Some(OwnedMessage::Close(_))));
+        assert!(sent[2].is_none());
+    }
 }
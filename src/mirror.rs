@@ -0,0 +1,422 @@
+//! Mirrors of the `ws-tox-protocol` types, which carry no JSON Schema. They
+//! are only used for the schema, the tests check that they parse and write
+//! the same JSON as the types they mirror.
+//!
+//! Errors of the protocol are described by their name only.
+
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub enum UserStatus {
+    None,
+    Away,
+    Busy,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub enum ConnectionStatus {
+    None,
+    Tcp,
+    Udp,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub enum MessageType {
+    Normal,
+    Action,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub enum FileKind {
+    Data,
+    Avatar,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub enum FileControl {
+    Resume,
+    Pause,
+    Cancel,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub enum ConferenceType {
+    Text,
+    Av,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct Friend {
+    pub number: u32,
+    pub public_key: String,
+    pub name: String,
+    pub status: UserStatus,
+    pub status_message: String,
+    pub last_online: u64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct PeerInfo {
+    pub number: u32,
+    pub public_key: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ConferenceInfo {
+    pub number: u32,
+    pub kind: ConferenceType,
+    pub title: String,
+    pub peers: Vec<PeerInfo>,
+}
+
+/// Mirror of `ws_tox_protocol::Request`.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "request")]
+pub enum Request {
+    Info,
+    SetInfo {
+        nospam: String,
+        name: String,
+        status: UserStatus,
+        status_message: String,
+        friends: Vec<String>,
+    },
+    AddFriend { tox_id: String, message: String },
+    AddFriendNorequest { tox_id: String },
+    DeleteFriend { friend: u32 },
+    GetConnectionStatus,
+    GetAddress,
+    GetNospam,
+    SetNospam { nospam: String },
+    GetPublicKey,
+    SetName { name: String },
+    GetName,
+    SetStatusMessage { message: String },
+    GetStatusMessage,
+    SetStatus { status: UserStatus },
+    GetStatus,
+    FriendByPublicKey { public_key: String },
+    FriendExists { friend: u32 },
+    GetFriendPublicKey { friend: u32 },
+    GetFriendLastOnline { friend: u32 },
+    GetFriendName { friend: u32 },
+    GetFriendStatusMessage { friend: u32 },
+    GetFriendStatus { friend: u32 },
+    GetFriendConnectionStatus { friend: u32 },
+    SendFriendMessage { friend: u32, kind: MessageType, message: String },
+    ControlFile { friend: u32, file_number: u32, control: FileControl },
+    SeekFile { friend: u32, file_number: u32, position: u64 },
+    GetFileId { friend: u32, file_number: u32 },
+    SendFile { friend: u32, kind: FileKind, file_size: u64, file_name: String },
+    SendFileChunk { friend: u32, file_number: u32, position: u64, data: Vec<u8> },
+    SendAvatar { friend: u32, file_size: u64, file_hash: String },
+    NewConference,
+    DeleteConference { conference: u32 },
+    GetPeerList { conference: u32 },
+    ConferencePeerCount { conference: u32 },
+    GetPeerName { conference: u32, peer: u32 },
+    GetPeerPublicKey { conference: u32, peer: u32 },
+    IsOwnPeerNumber { conference: u32, peer_number: u32 },
+    InviteToConference { friend: u32, conference: u32 },
+    JoinConference { friend: u32, cookie: Vec<u8> },
+    SendConferenceMessage { conference: u32, kind: MessageType, message: String },
+    GetConferenceTitle { conference: u32 },
+    SetConferenceTitle { conference: u32, title: String },
+    GetConferenceList,
+    GetConferenceType { conference: u32 },
+}
+
+/// Mirror of `ws_tox_protocol::Response`.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "response")]
+pub enum Response {
+    Ok,
+    Info {
+        tox_id: String,
+        name: String,
+        status: UserStatus,
+        status_message: String,
+        friends: Vec<Friend>,
+    },
+    ConnectionStatus { status: ConnectionStatus },
+    Address { address: String },
+    Nospam { nospam: String },
+    PublicKey { public_key: String },
+    Name { name: String },
+    StatusMessage { status: String },
+    Status { status: UserStatus },
+    Friend { friend: u32 },
+    FriendExists { exists: bool },
+    LastOnline { last_online: u64 },
+    MessageSent { message_id: u32 },
+    FileId { id: String },
+    FileNumber { file_number: u32 },
+    Conference { conference: u32 },
+    ConferencePeerList { peers: Vec<PeerInfo> },
+    ConferencePeerCount { count: u32 },
+    ConferencePeerName { name: String },
+    ConferencePeerPublicKey { public_key: String },
+    IsOwnPeerNumber { is_own: bool },
+    ConferenceTitle { title: String },
+    ConferenceList { conferences: Vec<ConferenceInfo> },
+    ConferenceType { kind: ConferenceType },
+    FriendNotFoundError,
+    AddFriendError { error: String },
+    SendFriendMessageError { error: String },
+    FileControlError { error: String },
+    FileSeekError { error: String },
+    FileGetError { error: String },
+    FileSendError { error: String },
+    FileSendChunkError { error: String },
+    ConferencePeerQueryError { error: String },
+    ConferenceInviteError { error: String },
+    ConferenceJoinError { error: String },
+    ConferenceSendError { error: String },
+    ConferenceTitleError { error: String },
+}
+
+/// Mirror of `ws_tox_protocol::Event`.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "event")]
+pub enum Event {
+    SecretKey { secret_key: String },
+    ConnectionStatus { status: ConnectionStatus },
+    FriendRequest { public_key: String, message: String },
+    FriendMessage { friend: u32, kind: MessageType, message: String },
+    FriendName { friend: u32, name: String },
+    FriendStatusMessage { friend: u32, message: String },
+    FriendStatus { friend: u32, status: UserStatus },
+    FriendConnectionStatus { friend: u32, status: ConnectionStatus },
+    FriendTyping { friend: u32, is_typing: bool },
+    FriendReadReceipt { friend: u32, message_id: u32 },
+    FileControlReceive { friend: u32, file_number: u32, control: FileControl },
+    FileChunkRequest { friend: u32, file_number: u32, position: u64, length: usize },
+    FileReceive { friend: u32, file_number: u32, kind: FileKind, file_size: u64, file_name: String },
+    FileReceiveChunk { friend: u32, file_number: u32, position: u64, data: Vec<u8> },
+    ConferenceInvite { friend: u32, kind: ConferenceType, cookie: Vec<u8> },
+    ConferenceMessage { conference: u32, peer: u32, kind: MessageType, message: String },
+    ConferenceTitle { conference: u32, peer: u32, title: String },
+    ConferencePeerListChanged { conference: u32 },
+}
+
+/// A request of every variant. Friends, files, conferences and peers are
+/// numbers that do not exist in a new profile.
+#[cfg(test)]
+pub fn sample_requests() -> Vec<serde_json::Value> {
+    use serde_json::json;
+
+    let pk = "0".repeat(64);
+
+    vec![
+        json!({"request": "Info"}),
+        json!({"request": "SetInfo", "nospam": "0000000A", "name": "name", "status": "Away",
+            "status_message": "status", "friends": [pk]}),
+        json!({"request": "AddFriend", "tox_id": "0".repeat(76), "message": "hi"}),
+        json!({"request": "AddFriendNorequest", "tox_id": pk}),
+        json!({"request": "DeleteFriend", "friend": 42}),
+        json!({"request": "GetConnectionStatus"}),
+        json!({"request": "GetAddress"}),
+        json!({"request": "GetNospam"}),
+        json!({"request": "SetNospam", "nospam": "0000000A"}),
+        json!({"request": "GetPublicKey"}),
+        json!({"request": "SetName", "name": "name"}),
+        json!({"request": "GetName"}),
+        json!({"request": "SetStatusMessage", "message": "status"}),
+        json!({"request": "GetStatusMessage"}),
+        json!({"request": "SetStatus", "status": "Busy"}),
+        json!({"request": "GetStatus"}),
+        json!({"request": "FriendByPublicKey", "public_key": pk}),
+        json!({"request": "FriendExists", "friend": 42}),
+        json!({"request": "GetFriendPublicKey", "friend": 42}),
+        json!({"request": "GetFriendLastOnline", "friend": 42}),
+        json!({"request": "GetFriendName", "friend": 42}),
+        json!({"request": "GetFriendStatusMessage", "friend": 42}),
+        json!({"request": "GetFriendStatus", "friend": 42}),
+        json!({"request": "GetFriendConnectionStatus", "friend": 42}),
+        json!({"request": "SendFriendMessage", "friend": 42, "kind": "Normal", "message": "hi"}),
+        json!({"request": "ControlFile", "friend": 42, "file_number": 7, "control": "Cancel"}),
+        json!({"request": "SeekFile", "friend": 42, "file_number": 7, "position": 9}),
+        json!({"request": "GetFileId", "friend": 42, "file_number": 7}),
+        json!({"request": "SendFile", "friend": 42, "kind": "Data", "file_size": 10,
+            "file_name": "file"}),
+        json!({"request": "SendFileChunk", "friend": 42, "file_number": 7, "position": 0,
+            "data": [1, 2, 3]}),
+        json!({"request": "SendAvatar", "friend": 42, "file_size": 10, "file_hash": pk}),
+        json!({"request": "NewConference"}),
+        json!({"request": "DeleteConference", "conference": 42}),
+        json!({"request": "GetPeerList", "conference": 42}),
+        json!({"request": "ConferencePeerCount", "conference": 42}),
+        json!({"request": "GetPeerName", "conference": 42, "peer": 3}),
+        json!({"request": "GetPeerPublicKey", "conference": 42, "peer": 3}),
+        json!({"request": "IsOwnPeerNumber", "conference": 42, "peer_number": 3}),
+        json!({"request": "InviteToConference", "friend": 42, "conference": 42}),
+        json!({"request": "JoinConference", "friend": 42, "cookie": [1, 2, 3]}),
+        json!({"request": "SendConferenceMessage", "conference": 42, "kind": "Action",
+            "message": "hi"}),
+        json!({"request": "GetConferenceTitle", "conference": 42}),
+        json!({"request": "SetConferenceTitle", "conference": 42, "title": "title"}),
+        json!({"request": "GetConferenceList"}),
+        json!({"request": "GetConferenceType", "conference": 42}),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::de::DeserializeOwned;
+    use serde_json::{json, Value};
+
+    /// A response of every variant.
+    fn sample_responses() -> Vec<Value> {
+        let pk = "0".repeat(64);
+        let peer = json!({"number": 0, "public_key": pk, "name": "peer"});
+
+        vec![
+            json!({"response": "Ok"}),
+            json!({"response": "Info", "tox_id": "0".repeat(76), "name": "name", "status": "None",
+                "status_message": "status", "friends": [{"number": 0, "public_key": pk,
+                "name": "friend", "status": "Away", "status_message": "status", "last_online": 9}]}),
+            json!({"response": "ConnectionStatus", "status": "Udp"}),
+            json!({"response": "Address", "address": "0".repeat(76)}),
+            json!({"response": "Nospam", "nospam": "0000000A"}),
+            json!({"response": "PublicKey", "public_key": pk}),
+            json!({"response": "Name", "name": "name"}),
+            json!({"response": "StatusMessage", "status": "status"}),
+            json!({"response": "Status", "status": "Busy"}),
+            json!({"response": "Friend", "friend": 1}),
+            json!({"response": "FriendExists", "exists": true}),
+            json!({"response": "LastOnline", "last_online": 9}),
+            json!({"response": "MessageSent", "message_id": 1}),
+            json!({"response": "FileId", "id": pk}),
+            json!({"response": "FileNumber", "file_number": 1}),
+            json!({"response": "Conference", "conference": 1}),
+            json!({"response": "ConferencePeerList", "peers": [peer]}),
+            json!({"response": "ConferencePeerCount", "count": 1}),
+            json!({"response": "ConferencePeerName", "name": "peer"}),
+            json!({"response": "ConferencePeerPublicKey", "public_key": pk}),
+            json!({"response": "IsOwnPeerNumber", "is_own": false}),
+            json!({"response": "ConferenceTitle", "title": "title"}),
+            json!({"response": "ConferenceList", "conferences": [{"number": 1, "kind": "Text",
+                "title": "title", "peers": [peer]}]}),
+            json!({"response": "ConferenceType", "kind": "Av"}),
+            json!({"response": "FriendNotFoundError"}),
+        ]
+    }
+
+    /// The variant names of a protocol enum tagged with `tag`, taken from the
+    /// error serde gives for an unknown variant, which lists all of them.
+    fn protocol_variants<P: DeserializeOwned>(tag: &str) -> Vec<String> {
+        let error = serde_json::from_value::<P>(json!({ tag: "NoSuchVariant" }))
+            .err()
+            .map(|e| e.to_string())
+            .unwrap_or_default();
+
+        error.split("expected one of ").nth(1)
+            .map(|list| list.split(", ")
+                .map(|name| name.trim_matches('`').to_owned())
+                .collect())
+            .unwrap_or_default()
+    }
+
+    /// An event of every variant.
+    fn sample_events() -> Vec<Value> {
+        let pk = "0".repeat(64);
+
+        vec![
+            json!({"event": "SecretKey", "secret_key": pk}),
+            json!({"event": "ConnectionStatus", "status": "Tcp"}),
+            json!({"event": "FriendRequest", "public_key": pk, "message": "hi"}),
+            json!({"event": "FriendMessage", "friend": 1, "kind": "Normal", "message": "hi"}),
+            json!({"event": "FriendName", "friend": 1, "name": "name"}),
+            json!({"event": "FriendStatusMessage", "friend": 1, "message": "status"}),
+            json!({"event": "FriendStatus", "friend": 1, "status": "Away"}),
+            json!({"event": "FriendConnectionStatus", "friend": 1, "status": "None"}),
+            json!({"event": "FriendTyping", "friend": 1, "is_typing": true}),
+            json!({"event": "FriendReadReceipt", "friend": 1, "message_id": 2}),
+            json!({"event": "FileControlReceive", "friend": 1, "file_number": 2, "control": "Pause"}),
+            json!({"event": "FileChunkRequest", "friend": 1, "file_number": 2, "position": 0,
+                "length": 10}),
+            json!({"event": "FileReceive", "friend": 1, "file_number": 2, "kind": "Avatar",
+                "file_size": 10, "file_name": "avatar.png"}),
+            json!({"event": "FileReceiveChunk", "friend": 1, "file_number": 2, "position": 0,
+                "data": [1, 2, 3]}),
+            json!({"event": "ConferenceInvite", "friend": 1, "kind": "Text", "cookie": [1, 2, 3]}),
+            json!({"event": "ConferenceMessage", "conference": 1, "peer": 0, "kind": "Normal",
+                "message": "hi"}),
+            json!({"event": "ConferenceTitle", "conference": 1, "peer": 0, "title": "title"}),
+            json!({"event": "ConferencePeerListChanged", "conference": 1}),
+        ]
+    }
+
+    fn roundtrip<T: Serialize + DeserializeOwned>(value: &Value) -> Value {
+        let parsed: T = serde_json::from_value(value.clone())
+            .unwrap_or_else(|e| panic!("{} does not parse: {}", value, e));
+
+        serde_json::to_value(parsed).unwrap()
+    }
+
+    /// Whether the response only carries the name of an error, which is not
+    /// mirrored.
+    fn is_error(name: &str) -> bool {
+        name.ends_with("Error") && name != "FriendNotFoundError"
+    }
+
+    /// Every sample parses into the protocol type and its mirror alike, every
+    /// variant of the mirror but the errors has a sample and every variant of
+    /// the protocol is mirrored.
+    fn check<P, M>(tag: &str, samples: Vec<Value>)
+    where
+        P: Serialize + DeserializeOwned,
+        M: Serialize + DeserializeOwned + JsonSchema,
+    {
+        for sample in &samples {
+            assert_eq!(roundtrip::<P>(sample), roundtrip::<M>(sample), "{}", sample);
+        }
+
+        let mirrored = crate::variant::names::<M>(tag);
+        for name in mirrored.iter().filter(|name| !is_error(name)) {
+            assert!(samples.iter().any(|s| s[tag] == name.as_str()), "no sample of {}", name);
+        }
+
+        let variants = protocol_variants::<P>(tag);
+        assert!(!variants.is_empty(), "the variants of the protocol are unknown");
+        for name in variants {
+            assert!(mirrored.contains(&name), "{} is not mirrored", name);
+        }
+    }
+
+    #[test]
+    fn requests_match_the_protocol() {
+        check::<crate::protocol::Request, Request>("request", sample_requests())
+    }
+
+    #[test]
+    fn responses_match_the_protocol() {
+        check::<crate::protocol::Response, Response>("response", sample_responses())
+    }
+
+    #[test]
+    fn error_responses_exist_in_the_protocol() {
+        let errors = crate::variant::names::<Response>("response").into_iter()
+            .filter(|name| is_error(name));
+        for name in errors {
+            // no error is named like this, so only the variant can be found
+            let sample = json!({"response": name, "error": "NoSuchError"});
+            let error = serde_json::from_value::<crate::protocol::Response>(sample)
+                .err()
+                .map(|e| e.to_string())
+                .unwrap_or_default();
+
+            assert!(!error.contains("unknown variant `"), "{}: {}", name, error);
+            assert!(error.contains("NoSuchError"), "{}: {}", name, error);
+        }
+    }
+
+    #[test]
+    fn events_match_the_protocol() {
+        check::<crate::protocol::Event, Event>("event", sample_events())
+    }
+}
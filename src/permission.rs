@@ -0,0 +1,196 @@
+use serde::Deserialize;
+
+use crate::ext::{ClientRequest, ServerRequest};
+use crate::protocol::Request;
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    /// Every request is allowed.
+    Full,
+    /// Only requests that do not change the tox state are allowed.
+    ReadOnly,
+}
+
+/// Which requests a client may issue.
+///
+/// Requests named in `deny` are always denied and requests named in `allow`
+/// are always allowed. The rest are decided by the `level`.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    pub level: Level,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            level: Level::Full,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+/// Name of the request variant, e.g. `DeleteFriend`.
+pub fn request_name(request: &Request) -> String {
+    crate::variant::name(request, "request")
+}
+
+fn is_read_only(request: &Request) -> bool {
+    use Request as R;
+
+    matches!(request,
+        R::Info |
+        R::GetConnectionStatus |
+        R::GetAddress |
+        R::GetNospam |
+        R::GetPublicKey |
+        R::GetName |
+        R::GetStatusMessage |
+        R::GetStatus |
+        R::FriendByPublicKey { .. } |
+        R::FriendExists { .. } |
+        R::GetFriendPublicKey { .. } |
+        R::GetFriendLastOnline { .. } |
+        R::GetFriendName { .. } |
+        R::GetFriendStatusMessage { .. } |
+        R::GetFriendStatus { .. } |
+        R::GetFriendConnectionStatus { .. } |
+        R::GetFileId { .. } |
+        R::GetPeerList { .. } |
+        R::ConferencePeerCount { .. } |
+        R::GetPeerName { .. } |
+        R::GetPeerPublicKey { .. } |
+        R::IsOwnPeerNumber { .. } |
+        R::GetConferenceTitle { .. } |
+        R::GetConferenceList |
+        R::GetConferenceType { .. }
+    )
+}
+
+impl Policy {
+    /// Whether every request is allowed.
+    pub fn is_full(&self) -> bool {
+        self.level == Level::Full && self.deny.is_empty()
+    }
+
+    /// Check the request, returning the name of a denied request.
+    pub fn check(&self, request: &ClientRequest) -> Result<(), String> {
+        match request {
+            ClientRequest::Tox(request) => self.check_tox(request),
+            // a snapshot holds the answers of these requests
+            ClientRequest::Server(ServerRequest::Snapshot) => {
+                self.check_tox(&Request::Info)?;
+                self.check_tox(&Request::GetConnectionStatus)?;
+                self.check_tox(&Request::GetConferenceList)
+            },
+            // the other server requests are checked where they run tox
+            // requests, e.g. every request of a `Batch`
+            ClientRequest::Server(_) => Ok(()),
+        }
+    }
+
+    fn check_tox(&self, request: &Request) -> Result<(), String> {
+        let name = request_name(request);
+
+        let is_allowed =
+            if self.deny.iter().any(|n| *n == name) { false }
+            else if self.allow.iter().any(|n| *n == name) { true }
+            else { self.level == Level::Full || is_read_only(request) };
+
+        if is_allowed { Ok(()) } else { Err(name) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn request(request: serde_json::Value) -> ClientRequest {
+        serde_json::from_value(request).unwrap()
+    }
+
+    fn policy(level: Level, allow: &[&str], deny: &[&str]) -> Policy {
+        Policy {
+            level,
+            allow: allow.iter().map(|name| name.to_string()).collect(),
+            deny: deny.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    fn set_name() -> ClientRequest {
+        request(json!({"request": "SetName", "name": "name"}))
+    }
+
+    fn get_name() -> ClientRequest {
+        request(json!({"request": "GetName"}))
+    }
+
+    #[test]
+    fn only_getters_are_read_only() {
+        for sample in crate::mirror::sample_requests() {
+            let name = sample["request"].as_str().unwrap().to_owned();
+            let request: Request = serde_json::from_value(sample).unwrap();
+            let others = [
+                "Info", "FriendByPublicKey", "FriendExists", "ConferencePeerCount",
+                "IsOwnPeerNumber",
+            ];
+            let is_getter = name.starts_with("Get") || others.contains(&name.as_str());
+
+            assert_eq!(is_read_only(&request), is_getter, "{}", name);
+        }
+    }
+
+    #[test]
+    fn levels_decide_unlisted_requests() {
+        let full = Policy::default();
+        let read_only = policy(Level::ReadOnly, &[], &[]);
+
+        assert_eq!(full.check(&set_name()), Ok(()));
+        assert_eq!(read_only.check(&get_name()), Ok(()));
+        assert_eq!(read_only.check(&set_name()), Err("SetName".to_owned()));
+        assert!(full.is_full());
+        assert!(!read_only.is_full());
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let both = policy(Level::Full, &["GetName"], &["GetName"]);
+        let allowed = policy(Level::ReadOnly, &["SetName"], &[]);
+        let denied = policy(Level::Full, &[], &["GetName"]);
+
+        assert_eq!(both.check(&get_name()), Err("GetName".to_owned()));
+        assert_eq!(allowed.check(&set_name()), Ok(()));
+        assert_eq!(denied.check(&get_name()), Err("GetName".to_owned()));
+        assert_eq!(denied.check(&set_name()), Ok(()));
+        assert!(!denied.is_full());
+    }
+
+    #[test]
+    fn snapshots_need_their_requests() {
+        let snapshot = request(json!({"request": "Snapshot"}));
+
+        assert_eq!(policy(Level::ReadOnly, &[], &[]).check(&snapshot), Ok(()));
+        for name in &["Info", "GetConnectionStatus", "GetConferenceList"] {
+            let denied = policy(Level::Full, &[], &[*name]);
+
+            assert_eq!(denied.check(&snapshot), Err(name.to_string()));
+        }
+    }
+
+    #[test]
+    fn other_server_requests_are_allowed() {
+        let read_only = policy(Level::ReadOnly, &[], &["GetName"]);
+        let status = request(json!({"request": "SessionStatus"}));
+        let batch = request(json!({"request": "Batch", "requests": [{"request": "GetName"}]}));
+
+        assert_eq!(read_only.check(&status), Ok(()));
+        // its requests are checked one by one when they run
+        assert_eq!(read_only.check(&batch), Ok(()));
+    }
+}
@@ -0,0 +1,326 @@
+use serde::{Serialize, Deserialize};
+
+use std::fs;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Public information about a stored profile.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProfileInfo {
+    pub public_key: String,
+    pub tox_id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Metadata {
+    name: String,
+    tox_id: String,
+    secret_key: String,
+}
+
+/// A newly created profile together with its secret key.
+pub struct NewProfile {
+    pub info: ProfileInfo,
+    pub secret_key: String,
+}
+
+/// Profiles stored on disk. Each profile is a `<public key>.json` metadata
+/// file and, once a session of it ran, a `<public key>.tox` file with the
+/// tox save data.
+#[derive(Clone)]
+pub struct ProfileStore {
+    dir: PathBuf
+}
+
+fn public_key_of(tox_id: &str) -> String {
+    tox_id.chars().take(64).collect()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// The Tox ID of a public key with a nospam: both followed by a checksum
+/// that XORs them two bytes at a time.
+fn tox_id_of(public_key: &[u8], nospam: &[u8]) -> String {
+    let mut checksum = [0; 2];
+    for (i, byte) in public_key.iter().chain(nospam).enumerate() {
+        checksum[i % 2] ^= byte;
+    }
+
+    to_hex(&[public_key, nospam, &checksum[..]].concat())
+}
+
+fn not_found() -> IoError {
+    IoError::new(IoErrorKind::NotFound, "profile not found")
+}
+
+/// Derive the public key from a secret key, both as hex strings.
+fn public_key_from(secret_key: &str) -> Option<String> {
+    use sodiumoxide::crypto::scalarmult::curve25519::{scalarmult_base, Scalar};
+
+    if secret_key.len() != 64 || !secret_key.is_ascii() {
+        return None
+    }
+
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&secret_key[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    let public_key = scalarmult_base(&Scalar(bytes));
+
+    // formatted the way tox formats its keys
+    let public_key: rstox::core::PublicKey = to_hex(&public_key.0).parse().ok()?;
+
+    Some(format!("{}", public_key))
+}
+
+/// Write a file only its owner may read, since profile files hold the
+/// secret key.
+fn write_private(path: &Path, data: &[u8]) -> Result<(), IoError> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+
+    // files created before keep their mode otherwise
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+
+    file.write_all(data)
+}
+
+impl ProfileStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        ProfileStore { dir: dir.into() }
+    }
+
+    fn metadata_path(&self, public_key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", public_key))
+    }
+
+    fn savedata_path(&self, public_key: &str) -> PathBuf {
+        self.dir.join(format!("{}.tox", public_key))
+    }
+
+    fn read_metadata(&self, public_key: &str) -> Result<Metadata, IoError> {
+        let file = fs::File::open(self.metadata_path(public_key))?;
+
+        serde_json::from_reader(file)
+            .map_err(|e| IoError::new(IoErrorKind::InvalidData, e))
+    }
+
+    fn write_metadata(&self, public_key: &str, metadata: &Metadata) -> Result<(), IoError> {
+        let data = serde_json::to_vec_pretty(metadata)
+            .map_err(|e| IoError::new(IoErrorKind::InvalidData, e))?;
+
+        write_private(&self.metadata_path(public_key), &data)
+    }
+
+    /// A public key is a hex string, so it is safe to use in a file name.
+    fn check_public_key(public_key: &str) -> Result<(), IoError> {
+        if public_key.len() == 64 && public_key.chars().all(|c| c.is_ascii_hexdigit()) {
+            Ok(())
+        }
+        else {
+            Err(not_found())
+        }
+    }
+
+    /// Generate new keys and store them as a profile. It gets its save data
+    /// once a session of it stops.
+    pub fn create(&self, name: String) -> Result<NewProfile, IoError> {
+        use sodiumoxide::crypto::box_;
+
+        sodiumoxide::init()
+            .map_err(|()| IoError::new(IoErrorKind::Other, "sodium init err"))?;
+        fs::create_dir_all(&self.dir)?;
+
+        let (public_key, secret_key) = box_::gen_keypair();
+        let nospam = sodiumoxide::randombytes::randombytes(4);
+
+        // formatted the way tox formats its keys, so sessions find them
+        let tox_id: rstox::core::Address = tox_id_of(&public_key.0, &nospam).parse()
+            .map_err(|e| IoError::new(IoErrorKind::Other, format!("tox id err: {:?}", e)))?;
+        let secret_key: rstox::core::SecretKey = to_hex(&secret_key.0).parse()
+            .map_err(|e| IoError::new(IoErrorKind::Other, format!("secret key err: {:?}", e)))?;
+        let tox_id = format!("{}", tox_id);
+        let secret_key = format!("{}", secret_key);
+        let public_key = public_key_of(&tox_id);
+
+        let metadata = Metadata {
+            name: name.clone(),
+            tox_id: tox_id.clone(),
+            secret_key: secret_key.clone(),
+        };
+        self.write_metadata(&public_key, &metadata)?;
+
+        let info = ProfileInfo { public_key, tox_id, name };
+
+        Ok(NewProfile { info, secret_key })
+    }
+
+    pub fn list(&self) -> Result<Vec<ProfileInfo>, IoError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut profiles = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue
+            }
+
+            let public_key = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(public_key) => public_key.to_owned(),
+                None => continue,
+            };
+
+            if let Ok(metadata) = self.read_metadata(&public_key) {
+                profiles.push(ProfileInfo {
+                    public_key,
+                    tox_id: metadata.tox_id,
+                    name: metadata.name,
+                })
+            }
+        }
+
+        Ok(profiles)
+    }
+
+    pub fn rename(&self, public_key: &str, name: String) -> Result<ProfileInfo, IoError> {
+        Self::check_public_key(public_key)?;
+
+        let mut metadata = self.read_metadata(public_key)?;
+        metadata.name = name;
+        self.write_metadata(public_key, &metadata)?;
+
+        Ok(ProfileInfo {
+            public_key: public_key.to_owned(),
+            tox_id: metadata.tox_id,
+            name: metadata.name,
+        })
+    }
+
+    /// Remove the profile and all its data, returning its secret key.
+    pub fn delete(&self, public_key: &str) -> Result<String, IoError> {
+        Self::check_public_key(public_key)?;
+
+        let metadata = self.read_metadata(public_key)?;
+
+        fs::remove_file(self.metadata_path(public_key))?;
+        match fs::remove_file(self.savedata_path(public_key)) {
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => (),
+            r => r?,
+        }
+
+        Ok(metadata.secret_key)
+    }
+
+    /// Find the public key of the profile with the given secret key.
+    pub fn find(&self, secret_key: &str) -> Option<String> {
+        let public_key = public_key_from(secret_key)?;
+        let metadata = self.read_metadata(&public_key).ok()?;
+
+        if metadata.secret_key.eq_ignore_ascii_case(secret_key) {
+            Some(public_key)
+        }
+        else {
+            None
+        }
+    }
+
+    /// The nospam of the stored Tox ID, which a profile without save data
+    /// starts with.
+    pub fn nospam(&self, public_key: &str) -> Option<String> {
+        let metadata = self.read_metadata(public_key).ok()?;
+
+        metadata.tox_id.get(64..72).map(|nospam| nospam.to_owned())
+    }
+
+    pub fn load(&self, public_key: &str) -> Option<Vec<u8>> {
+        fs::read(self.savedata_path(public_key)).ok()
+    }
+
+    /// Store the save data, unless the profile has been deleted meanwhile.
+    pub fn save(&self, public_key: &str, data: &[u8]) -> Result<(), IoError> {
+        if !self.metadata_path(public_key).exists() {
+            return Err(not_found())
+        }
+
+        write_private(&self.savedata_path(public_key), data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_keys_are_derived() {
+        use sodiumoxide::crypto::box_;
+
+        sodiumoxide::init().unwrap();
+        let (public_key, secret_key) = box_::gen_keypair();
+        let derived = public_key_from(&to_hex(&secret_key.0)).unwrap();
+
+        assert!(derived.eq_ignore_ascii_case(&to_hex(&public_key.0)));
+        assert_eq!(public_key_from("00"), None);
+        assert_eq!(public_key_from(&"x".repeat(64)), None);
+        assert_eq!(public_key_from(&"é".repeat(32)), None);
+    }
+
+    #[test]
+    fn tox_ids_end_with_a_checksum() {
+        let public_key = [0; 32];
+        let tox_id = tox_id_of(&public_key, &[0x12, 0x34, 0x56, 0x78]);
+
+        assert_eq!(tox_id, format!("{}12345678{}", "0".repeat(64), "444C"));
+    }
+
+    #[test]
+    fn created_profiles_are_found() {
+        let dir = std::env::temp_dir().join(format!("ws-tox-profiles-{}", std::process::id()));
+        let store = ProfileStore::new(&dir);
+
+        let profile = store.create("bot".to_owned()).unwrap();
+        let found = store.find(&profile.secret_key);
+        let nospam = store.nospam(&profile.info.public_key);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(found.as_ref(), Some(&profile.info.public_key));
+        assert_eq!(profile.info.tox_id.len(), 76);
+        assert!(profile.info.tox_id.starts_with(&profile.info.public_key));
+        assert_eq!(nospam.as_deref(), profile.info.tox_id.get(64..72));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn files_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("ws-tox-{}.json", std::process::id()));
+
+        fs::write(&path, b"{}").unwrap();
+        write_private(&path, b"{}").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,296 @@
+//! Bounded queue of the answers a session sends to its client.
+
+use futures::{Async, Poll, Stream};
+use futures::task::AtomicTask;
+use serde::Deserialize;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::config::AnswerQueueConfig;
+use crate::protocol::Event;
+use crate::tox::Answer;
+
+/// What happens to a client that does not read its answers fast enough to
+/// keep the queue below its capacity.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest queued events that are safe to lose, like typing
+    /// notifications, to make room. Other answers are always queued.
+    DropEvents,
+    /// Pause incoming file transfers until the queue drained to half of its
+    /// capacity. Nothing is dropped.
+    PauseTransfers,
+    /// Disconnect the client, dropping the queued answers.
+    Disconnect,
+}
+
+impl Default for SlowConsumerPolicy {
+    fn default() -> Self {
+        SlowConsumerPolicy::DropEvents
+    }
+}
+
+/// The outcome of queueing an answer.
+pub enum Push {
+    /// The answer is queued, `dropped` answers were dropped to make room.
+    Queued { dropped: usize },
+    /// The client is gone or was disconnected, dropping `dropped` answers.
+    Closed { dropped: usize },
+}
+
+#[derive(Default)]
+struct State {
+    answers: VecDeque<Answer>,
+    is_closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    task: AtomicTask,
+}
+
+impl Shared {
+    fn close(&self) {
+        self.state.lock().unwrap().is_closed = true;
+        self.task.notify()
+    }
+}
+
+/// The sending half, owned by the session.
+pub struct AnswerSender {
+    shared: Arc<Shared>,
+    config: AnswerQueueConfig,
+}
+
+/// The receiving half, a stream of the answers owned by the connection. It
+/// ends when the sender is dropped.
+pub struct AnswerReceiver {
+    shared: Arc<Shared>,
+}
+
+pub fn channel(config: &AnswerQueueConfig) -> (AnswerSender, AnswerReceiver) {
+    let shared = Arc::new(Shared::default());
+    let sender = AnswerSender {
+        shared: shared.clone(),
+        config: config.clone(),
+    };
+
+    (sender, AnswerReceiver { shared })
+}
+
+/// Events that only tell about a passing state, which the client learns
+/// again with the next such event or a `Snapshot`.
+fn is_droppable(answer: &Answer) -> bool {
+    matches!(answer,
+        Answer::Event(Event::FriendTyping { .. }) |
+        Answer::Event(Event::FriendStatus { .. }) |
+        Answer::Event(Event::FriendStatusMessage { .. })
+    )
+}
+
+impl AnswerSender {
+    fn disconnect(&self, mut state: std::sync::MutexGuard<State>) -> Push {
+        let dropped = state.answers.len() + 1;
+
+        state.answers.clear();
+        state.is_closed = true;
+        drop(state);
+        self.shared.task.notify();
+
+        Push::Closed { dropped }
+    }
+
+    pub fn push(&self, answer: Answer) -> Push {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state.is_closed {
+            return Push::Closed { dropped: 0 }
+        }
+
+        // whatever the policy, the queue never grows past its hard limit
+        if state.answers.len() >= self.config.hard_limit.max(self.config.capacity) {
+            return self.disconnect(state)
+        }
+
+        let mut dropped = 0;
+        if state.answers.len() >= self.config.capacity {
+            match self.config.slow_consumer {
+                SlowConsumerPolicy::DropEvents => {
+                    let oldest = state.answers.iter().position(is_droppable);
+
+                    if let Some(oldest) = oldest {
+                        state.answers.remove(oldest);
+                        dropped = 1;
+                    }
+                    else if is_droppable(&answer) {
+                        return Push::Queued { dropped: 1 }
+                    }
+                },
+                SlowConsumerPolicy::PauseTransfers => {},
+                SlowConsumerPolicy::Disconnect => return self.disconnect(state),
+            }
+        }
+
+        state.answers.push_back(answer);
+        drop(state);
+        self.shared.task.notify();
+
+        Push::Queued { dropped }
+    }
+
+    /// Whether transfers should be paused because the queue is full.
+    pub fn is_congested(&self) -> bool {
+        self.config.slow_consumer == SlowConsumerPolicy::PauseTransfers
+            && self.shared.state.lock().unwrap().answers.len() >= self.config.capacity
+    }
+
+    /// Whether paused transfers may be resumed.
+    pub fn is_drained(&self) -> bool {
+        self.shared.state.lock().unwrap().answers.len() <= self.config.capacity / 2
+    }
+}
+
+impl Drop for AnswerSender {
+    fn drop(&mut self) {
+        self.shared.close()
+    }
+}
+
+impl Stream for AnswerReceiver {
+    type Item = Answer;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Answer>, ()> {
+        // register first, so a push after the check below wakes the task
+        self.shared.task.register();
+
+        let mut state = self.shared.state.lock().unwrap();
+        match state.answers.pop_front() {
+            Some(answer) => Ok(Async::Ready(Some(answer))),
+            None if state.is_closed => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Drop for AnswerReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+
+        state.answers.clear();
+        state.is_closed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn config(
+        capacity: usize,
+        hard_limit: usize,
+        slow_consumer: SlowConsumerPolicy,
+    ) -> AnswerQueueConfig {
+        AnswerQueueConfig { capacity, hard_limit, slow_consumer }
+    }
+
+    fn event(event: serde_json::Value) -> Answer {
+        Answer::Event(serde_json::from_value(event).unwrap())
+    }
+
+    fn typing() -> Answer {
+        event(json!({"event": "FriendTyping", "friend": 0, "is_typing": true}))
+    }
+
+    fn message() -> Answer {
+        event(json!({"event": "FriendMessage", "friend": 0, "kind": "Normal", "message": "hi"}))
+    }
+
+    /// The names of the queued answers, once the sender is gone.
+    fn received(rx: AnswerReceiver) -> Vec<String> {
+        rx.wait()
+            .map(|answer| crate::variant::name(&answer.unwrap(), "event"))
+            .collect()
+    }
+
+    #[test]
+    fn the_oldest_droppable_events_are_dropped() {
+        let (tx, rx) = channel(&config(2, 8, SlowConsumerPolicy::DropEvents));
+
+        assert!(matches!(tx.push(typing()), Push::Queued { dropped: 0 }));
+        assert!(matches!(tx.push(message()), Push::Queued { dropped: 0 }));
+        assert!(matches!(tx.push(message()), Push::Queued { dropped: 1 }));
+        // nothing left to drop but the new event itself
+        assert!(matches!(tx.push(typing()), Push::Queued { dropped: 1 }));
+        // other answers are always queued
+        assert!(matches!(tx.push(message()), Push::Queued { dropped: 0 }));
+        assert!(!tx.is_congested());
+
+        drop(tx);
+        assert_eq!(received(rx), vec!["FriendMessage"; 3]);
+    }
+
+    #[test]
+    fn the_hard_limit_disconnects() {
+        let (tx, rx) = channel(&config(1, 3, SlowConsumerPolicy::PauseTransfers));
+
+        for _ in 0..3 {
+            assert!(matches!(tx.push(message()), Push::Queued { dropped: 0 }));
+        }
+        assert!(matches!(tx.push(message()), Push::Closed { dropped: 4 }));
+        assert!(matches!(tx.push(message()), Push::Closed { dropped: 0 }));
+
+        drop(tx);
+        assert!(received(rx).is_empty());
+    }
+
+    #[test]
+    fn slow_clients_are_disconnected() {
+        let (tx, rx) = channel(&config(2, 8, SlowConsumerPolicy::Disconnect));
+
+        assert!(matches!(tx.push(message()), Push::Queued { dropped: 0 }));
+        assert!(matches!(tx.push(typing()), Push::Queued { dropped: 0 }));
+        assert!(matches!(tx.push(message()), Push::Closed { dropped: 3 }));
+
+        drop(tx);
+        assert!(received(rx).is_empty());
+    }
+
+    #[test]
+    fn transfers_pause_until_half_is_drained() {
+        let (tx, mut rx) = channel(&config(4, 8, SlowConsumerPolicy::PauseTransfers));
+
+        for _ in 0..3 {
+            tx.push(typing());
+        }
+        assert!(!tx.is_congested());
+
+        // nothing is dropped past the capacity
+        assert!(matches!(tx.push(typing()), Push::Queued { dropped: 0 }));
+        assert!(matches!(tx.push(typing()), Push::Queued { dropped: 0 }));
+        assert!(tx.is_congested());
+        assert!(!tx.is_drained());
+
+        assert_eq!(rx.by_ref().take(3).wait().count(), 3);
+        assert!(!tx.is_congested());
+        assert!(tx.is_drained());
+
+        drop(tx);
+        assert_eq!(received(rx).len(), 2);
+    }
+
+    #[test]
+    fn other_policies_never_pause_transfers() {
+        let (tx, _rx) = channel(&config(1, 8, SlowConsumerPolicy::DropEvents));
+
+        tx.push(message());
+        tx.push(message());
+
+        assert!(!tx.is_congested());
+    }
+}
@@ -0,0 +1,39 @@
+//! JSON Schema of the wire protocol, generated from the Rust types. The
+//! types of `ws-tox-protocol` are described through their mirrors.
+
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Metadata, ObjectValidation, RootSchema, SchemaObject};
+
+use crate::encoding::TaggedAnswer;
+use crate::ext::RequestEnvelope;
+use crate::tox::Answer;
+
+/// The schema with the messages a client sends as `request` and the ones
+/// the server sends as `answer`, or as `tagged_answer` with a tagged
+/// encoding.
+pub fn schema() -> RootSchema {
+    let mut gen = SchemaGenerator::default();
+
+    let mut object = ObjectValidation::default();
+    object.properties.insert("request".to_owned(), gen.subschema_for::<RequestEnvelope>());
+    object.properties.insert("answer".to_owned(), gen.subschema_for::<Answer>());
+    object.properties.insert("tagged_answer".to_owned(), gen.subschema_for::<TaggedAnswer<'static>>());
+
+    RootSchema {
+        meta_schema: gen.settings().meta_schema.clone(),
+        schema: SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                title: Some(format!("ws-tox {}", env!("CARGO_PKG_VERSION"))),
+                ..Default::default()
+            })),
+            instance_type: Some(InstanceType::Object.into()),
+            object: Some(Box::new(object)),
+            ..Default::default()
+        },
+        definitions: gen.take_definitions(),
+    }
+}
+
+pub fn to_json() -> String {
+    serde_json::to_string_pretty(&schema()).unwrap()
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::ext::ServerResponse;
+use crate::limits::Permit;
+use crate::permission::Policy;
+use crate::queue::AnswerReceiver;
+use crate::tox::{AnswerSlot, ClientId, Incoming, ToxGuard, ToxHandle};
+
+/// Activity and traffic counters of a session.
+pub struct Stats {
+    started: Instant,
+    last_activity: Mutex<Instant>,
+    clients: AtomicUsize,
+    bytes_received: AtomicUsize,
+    bytes_sent: AtomicUsize,
+    answers_dropped: AtomicUsize,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        let now = Instant::now();
+
+        Stats {
+            started: now,
+            last_activity: Mutex::new(now),
+            clients: AtomicUsize::new(0),
+            bytes_received: AtomicUsize::new(0),
+            bytes_sent: AtomicUsize::new(0),
+            answers_dropped: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats::default()
+    }
+
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now()
+    }
+
+    pub fn connect(&self) {
+        self.clients.fetch_add(1, Ordering::SeqCst);
+        self.touch()
+    }
+
+    pub fn disconnect(&self) {
+        self.clients.fetch_sub(1, Ordering::SeqCst);
+        self.touch()
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+        self.touch()
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count answers dropped because the client was too slow.
+    pub fn dropped(&self, answers: usize) {
+        self.answers_dropped.fetch_add(answers, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        self.last_activity.lock().unwrap().elapsed()
+    }
+
+    fn is_abandoned(&self, ttl: Duration) -> bool {
+        self.clients.load(Ordering::SeqCst) == 0 && self.idle() > ttl
+    }
+
+    pub fn status(&self) -> ServerResponse {
+        ServerResponse::SessionStatus {
+            uptime: self.started.elapsed().as_secs(),
+            idle: self.idle().as_secs(),
+            clients: self.clients.load(Ordering::SeqCst),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            answers_dropped: self.answers_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A tox instance that lives independently of any WebSocket connection.
+struct Session {
+    request_tx: Sender<Incoming>,
+    answers: AnswerSlot,
+    stats: Arc<Stats>,
+    /// Headless sessions are never reaped.
+    is_headless: bool,
+    /// The slot of the session in the limits, headless sessions have none.
+    _permit: Option<Permit>,
+    _guard: ToxGuard,
+    thread: JoinHandle<()>,
+}
+
+impl Session {
+    fn attach(&self, policy: Arc<Policy>) -> Attached {
+        let (client, answer_rx) = self.answers.attach(policy);
+
+        Attached {
+            request_tx: self.request_tx.clone(),
+            client,
+            answer_rx,
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+/// A client attached to a session.
+pub struct Attached {
+    pub request_tx: Sender<Incoming>,
+    pub client: ClientId,
+    pub answer_rx: AnswerReceiver,
+    pub stats: Arc<Stats>,
+}
+
+/// Sessions that clients can attach to, keyed by their secret key.
+#[derive(Clone, Default)]
+pub struct Sessions {
+    inner: Arc<Mutex<HashMap<String, Session>>>
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Sessions::default()
+    }
+
+    /// Keep the tox instance running after its clients leave. Headless
+    /// sessions run until the server exits, others until they are reaped,
+    /// holding their permit until then.
+    ///
+    /// When another client started a session with the same key meanwhile,
+    /// the client is attached to that one and the new instance stops.
+    pub fn insert(&self, secret_key: String, handle: ToxHandle, permit: Option<Permit>) -> Attached {
+        let mut sessions = self.inner.lock().unwrap();
+
+        if let Some(session) = sessions.get(&secret_key) {
+            let policy = handle.answers.policy(handle.client).unwrap_or_default();
+
+            return session.attach(policy)
+        }
+
+        let is_headless = permit.is_none();
+        let ToxHandle { request_tx, client, answer_rx, answers, stats, guard, thread } = handle;
+        let attached = Attached {
+            request_tx: request_tx.clone(),
+            client,
+            answer_rx,
+            stats: stats.clone(),
+        };
+        let session = Session {
+            request_tx,
+            answers,
+            stats,
+            is_headless,
+            _permit: permit,
+            _guard: guard,
+            thread,
+        };
+
+        sessions.insert(secret_key, session);
+
+        attached
+    }
+
+    /// Stop the session, if it is running.
+    pub fn remove(&self, secret_key: &str) {
+        self.inner.lock().unwrap().remove(secret_key);
+    }
+
+    /// Attach a client with the policy to a running session, next to the
+    /// clients already attached.
+    pub fn attach(&self, secret_key: &str, policy: Arc<Policy>) -> Option<Attached> {
+        let sessions = self.inner.lock().unwrap();
+
+        Some(sessions.get(secret_key)?.attach(policy))
+    }
+
+    /// Stop the sessions that had no clients for longer than `ttl`. Their
+    /// tox threads save the profile before exiting.
+    pub fn reap(&self, ttl: Duration) {
+        self.inner.lock().unwrap()
+            .retain(|_, session| session.is_headless || !session.stats.is_abandoned(ttl))
+    }
+
+    /// Stop all sessions, headless ones included, and wait until their tox
+    /// threads saved the profiles.
+    pub fn stop_all(&self) {
+        let sessions: Vec<Session> = self.inner.lock().unwrap()
+            .drain()
+            .map(|(_, session)| session)
+            .collect();
+
+        for Session { thread, _guard: guard, .. } in sessions {
+            drop(guard);
+
+            if thread.join().is_err() {
+                tracing::error!("a tox thread panicked while stopping")
+            }
+        }
+    }
+
+    /// Reap idle sessions periodically on a separate thread.
+    pub fn spawn_reaper(&self, ttl: Duration) {
+        let sessions = self.clone();
+        // a zero ttl must not make the reaper spin
+        let period = ttl.min(Duration::from_secs(30)).max(Duration::from_secs(1));
+
+        std::thread::spawn(move || loop {
+            std::thread::sleep(period);
+            sessions.reap(ttl);
+        });
+    }
+}
@@ -0,0 +1,121 @@
+//! Session state that toxcore does not keep, collected for snapshots.
+
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+use crate::ext::ClientResponse;
+use crate::protocol::{ConnectionStatus, Event, FileControl, Request, Response};
+
+/// A friend request that was neither accepted nor answered yet.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct FriendRequest {
+    pub public_key: String,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// A file transfer that is neither finished nor cancelled.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct FileTransfer {
+    pub friend: u32,
+    pub file_number: u32,
+    pub direction: Direction,
+    pub file_size: u64,
+    pub file_name: String,
+}
+
+/// Friend requests and file transfers of a session, followed through its
+/// events, requests and responses.
+#[derive(Default)]
+pub struct Tracker {
+    friend_requests: Vec<FriendRequest>,
+    file_transfers: Vec<FileTransfer>,
+}
+
+impl Tracker {
+    fn remove_transfer(&mut self, friend: u32, file_number: u32) {
+        self.file_transfers.retain(|t| t.friend != friend || t.file_number != file_number)
+    }
+
+    pub fn event(&mut self, event: &Event) {
+        match event {
+            Event::FriendRequest { public_key, message } => {
+                self.friend_requests.retain(|r| r.public_key != *public_key);
+                self.friend_requests.push(FriendRequest {
+                    public_key: public_key.clone(),
+                    message: message.clone(),
+                })
+            },
+            Event::FileReceive { friend, file_number, file_size, file_name, .. } =>
+                self.file_transfers.push(FileTransfer {
+                    friend: *friend,
+                    file_number: *file_number,
+                    direction: Direction::Incoming,
+                    file_size: *file_size,
+                    file_name: file_name.clone(),
+                }),
+            // an empty chunk ends a transfer
+            Event::FileReceiveChunk { friend, file_number, data, .. } if data.is_empty() =>
+                self.remove_transfer(*friend, *file_number),
+            Event::FileChunkRequest { friend, file_number, length, .. } if *length == 0 =>
+                self.remove_transfer(*friend, *file_number),
+            Event::FileControlReceive { friend, file_number, control: FileControl::Cancel } =>
+                self.remove_transfer(*friend, *file_number),
+            // toxcore drops the transfers of a friend going offline
+            Event::FriendConnectionStatus { friend, status: ConnectionStatus::None } =>
+                self.file_transfers.retain(|t| t.friend != *friend),
+            _ => {},
+        }
+    }
+
+    pub fn request(&mut self, request: &Request, response: &ClientResponse) {
+        let file_number = match response {
+            ClientResponse::Tox(Response::FileNumber { file_number }) => Some(*file_number),
+            _ => None,
+        };
+
+        match (request, file_number) {
+            (Request::SendFile { friend, file_size, file_name, .. }, Some(file_number)) =>
+                self.file_transfers.push(FileTransfer {
+                    friend: *friend,
+                    file_number,
+                    direction: Direction::Outgoing,
+                    file_size: *file_size,
+                    file_name: file_name.clone(),
+                }),
+            (Request::SendAvatar { friend, file_size, .. }, Some(file_number)) =>
+                self.file_transfers.push(FileTransfer {
+                    friend: *friend,
+                    file_number,
+                    direction: Direction::Outgoing,
+                    file_size: *file_size,
+                    file_name: "avatar.png".to_owned(),
+                }),
+            (Request::ControlFile { friend, file_number, control: FileControl::Cancel }, _) =>
+                self.remove_transfer(*friend, *file_number),
+            _ => {},
+        }
+    }
+
+    /// The friend requests not accepted yet.
+    pub fn friend_requests(&mut self, tox: &mut rstox::core::Tox) -> Vec<FriendRequest> {
+        self.friend_requests.retain(|r| {
+            let is_friend = r.public_key.parse::<rstox::core::PublicKey>().ok()
+                .and_then(|pk| tox.friend_by_public_key(pk));
+
+            is_friend.is_none()
+        });
+
+        self.friend_requests.clone()
+    }
+
+    pub fn file_transfers(&self) -> Vec<FileTransfer> {
+        self.file_transfers.clone()
+    }
+}
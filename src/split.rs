@@ -0,0 +1,206 @@
+//! Splitting of messages longer than toxcore accepts into several messages.
+
+/// Split the message into parts of at most `max_length` bytes, or of one
+/// character if it is longer. Parts end after whitespace where possible and
+/// never inside a UTF-8 character, so the parts joined together give the
+/// message back.
+pub fn split(message: &str, max_length: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = message;
+
+    while rest.len() > max_length {
+        let mut end = max_length;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        // a character longer than `max_length` gets a part of its own
+        if end == 0 {
+            end = rest.chars().next().map_or(0, char::len_utf8);
+        }
+
+        let cut = match rest[..end].rfind(char::is_whitespace) {
+            Some(space) if space > 0 => {
+                let space_len = rest[space..].chars().next().map_or(1, char::len_utf8);
+                space + space_len
+            },
+            _ => end,
+        };
+
+        parts.push(&rest[..cut]);
+        rest = &rest[cut..];
+    }
+
+    if !rest.is_empty() || parts.is_empty() {
+        parts.push(rest);
+    }
+
+    parts
+}
+
+/// Send the parts in order with `send` until one fails. Returns what sending
+/// each part gave, e.g. the message ids, and the error of the failed part.
+/// The parts after a failed one are not sent.
+pub fn send_parts<T, E, F>(parts: Vec<&str>, mut send: F) -> (Vec<T>, Option<E>)
+where
+    F: FnMut(&str) -> Result<T, E>,
+{
+    let mut sent = Vec::new();
+
+    for part in parts {
+        match send(part) {
+            Ok(result) => sent.push(result),
+            Err(e) => return (sent, Some(e)),
+        }
+    }
+
+    (sent, None)
+}
+
+/// Split messages tracked at most, the oldest are forgotten first.
+const MAX_PENDING: usize = 256;
+
+/// A split friend message whose parts are not all read yet.
+struct Pending {
+    friend: u32,
+    message_ids: Vec<u32>,
+    unread: Vec<u32>,
+}
+
+/// Read receipts of split friend messages, so the client learns when a
+/// whole message was read.
+#[derive(Default)]
+pub struct Receipts {
+    pending: Vec<Pending>,
+}
+
+impl Receipts {
+    pub fn track(&mut self, friend: u32, message_ids: Vec<u32>) {
+        let unread = message_ids.clone();
+
+        if self.pending.len() >= MAX_PENDING {
+            self.pending.remove(0);
+        }
+        self.pending.push(Pending { friend, message_ids, unread })
+    }
+
+    /// Record the receipt of a part. Returns the ids of all parts once the
+    /// last one is read.
+    pub fn read(&mut self, friend: u32, message_id: u32) -> Option<Vec<u32>> {
+        let index = self.pending.iter()
+            .position(|p| p.friend == friend && p.unread.contains(&message_id))?;
+
+        let pending = &mut self.pending[index];
+        pending.unread.retain(|id| *id != message_id);
+
+        if pending.unread.is_empty() {
+            Some(self.pending.remove(index).message_ids)
+        }
+        else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_messages_are_kept() {
+        assert_eq!(split("", 4), vec![""]);
+        assert_eq!(split("abc", 4), vec!["abc"]);
+        // exactly the maximum length
+        assert_eq!(split("ab d", 4), vec!["ab d"]);
+    }
+
+    #[test]
+    fn parts_end_after_whitespace() {
+        assert_eq!(split("hello world", 8), vec!["hello ", "world"]);
+        assert_eq!(split("ab\u{3000}cd", 6), vec!["ab\u{3000}", "cd"]);
+    }
+
+    #[test]
+    fn long_words_are_cut() {
+        let message = "a".repeat(25);
+
+        assert_eq!(split(&message, 10), vec!["a".repeat(10), "a".repeat(10), "a".repeat(5)]);
+        assert_eq!(split("ab cdefghij", 4), vec!["ab ", "cdef", "ghij"]);
+    }
+
+    #[test]
+    fn characters_are_never_cut() {
+        // two bytes each, so the maximum falls inside a character
+        let message = "é".repeat(10);
+        let parts = split(&message, 5);
+
+        assert!(parts.iter().all(|part| part.len() == 4 || part.len() == 2));
+        assert_eq!(parts.concat(), message);
+
+        // a character longer than the maximum
+        assert_eq!(split("€€", 2), vec!["€", "€"]);
+    }
+
+    #[test]
+    fn parts_join_to_the_message() {
+        let message = "Lorem ipsum dolor sit amet, \u{1f600}\u{1f600} consectetur adipiscing";
+
+        for max_length in 1..message.len() + 1 {
+            let parts = split(message, max_length);
+
+            assert_eq!(parts.concat(), message);
+            assert!(parts.iter().all(|part| !part.is_empty()));
+        }
+    }
+
+    #[test]
+    fn parts_are_sent_until_one_fails() {
+        let mut tried = Vec::new();
+        let result = send_parts(vec!["a", "b", "c"], |part| {
+            tried.push(part.to_owned());
+            if part == "b" { Err("SendQ") } else { Ok(part.len()) }
+        });
+
+        assert_eq!(result, (vec![1], Some("SendQ")));
+        assert_eq!(tried, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn failing_first_parts_send_nothing() {
+        let result: (Vec<()>, _) = send_parts(vec!["a", "b"], |_| Err("NotConnected"));
+
+        assert_eq!(result, (vec![], Some("NotConnected")));
+    }
+
+    #[test]
+    fn all_parts_are_sent() {
+        let result = send_parts::<_, (), _>(split("ab cd ef", 3), |part| Ok(part.to_owned()));
+
+        assert_eq!(result, (vec!["ab ".to_owned(), "cd ".to_owned(), "ef".to_owned()], None));
+    }
+
+    #[test]
+    fn receipts_wait_for_every_part() {
+        let mut receipts = Receipts::default();
+        receipts.track(1, vec![10, 11, 12]);
+
+        assert_eq!(receipts.read(1, 11), None);
+        assert_eq!(receipts.read(1, 10), None);
+        // a repeated receipt or another friend changes nothing
+        assert_eq!(receipts.read(1, 10), None);
+        assert_eq!(receipts.read(2, 12), None);
+        assert_eq!(receipts.read(1, 12), Some(vec![10, 11, 12]));
+        assert_eq!(receipts.read(1, 12), None);
+    }
+
+    #[test]
+    fn receipts_forget_the_oldest_messages() {
+        let mut receipts = Receipts::default();
+
+        for friend in 0..MAX_PENDING as u32 + 1 {
+            receipts.track(friend, vec![friend]);
+        }
+
+        assert_eq!(receipts.read(0, 0), None);
+        assert_eq!(receipts.read(1, 1), Some(vec![1]));
+    }
+}
@@ -0,0 +1,267 @@
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+use std::collections::HashMap;
+
+use crate::protocol::Event;
+
+/// Restricts events to the listed friends and conferences. Events that do
+/// not concern a friend or a conference always pass.
+#[derive(Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Filter {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub friends: Option<Vec<u32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conferences: Option<Vec<u32>>,
+}
+
+impl Filter {
+    fn allows(list: &Option<Vec<u32>>, number: Option<u32>) -> bool {
+        match (list, number) {
+            (Some(list), Some(number)) => list.contains(&number),
+            _ => true,
+        }
+    }
+
+    fn matches(&self, event: &Event) -> bool {
+        let (friend, conference) = target(event);
+
+        Self::allows(&self.friends, friend) && Self::allows(&self.conferences, conference)
+    }
+}
+
+/// Name of the event variant, e.g. `FriendMessage`.
+pub fn event_name(event: &Event) -> String {
+    crate::variant::name(event, "event")
+}
+
+/// The friend and the conference the event concerns.
+fn target(event: &Event) -> (Option<u32>, Option<u32>) {
+    use Event as E;
+
+    match event {
+        E::FriendMessage { friend, .. } |
+        E::FriendName { friend, .. } |
+        E::FriendStatusMessage { friend, .. } |
+        E::FriendStatus { friend, .. } |
+        E::FriendConnectionStatus { friend, .. } |
+        E::FriendTyping { friend, .. } |
+        E::FriendReadReceipt { friend, .. } |
+        E::FileControlReceive { friend, .. } |
+        E::FileChunkRequest { friend, .. } |
+        E::FileReceive { friend, .. } |
+        E::FileReceiveChunk { friend, .. } |
+        E::ConferenceInvite { friend, .. } => (Some(*friend), None),
+        E::ConferenceMessage { conference, .. } |
+        E::ConferenceTitle { conference, .. } |
+        E::ConferencePeerListChanged { conference, .. } => (None, Some(*conference)),
+        _ => (None, None),
+    }
+}
+
+/// Whether the names are all names of tox events, the ones a client can
+/// subscribe to.
+pub fn are_known(events: &[String]) -> bool {
+    let known = crate::hello::tox_events();
+
+    events.iter().all(|event| known.contains(event))
+}
+
+/// The events a client wants to receive.
+///
+/// A client that never subscribed receives every event. The first
+/// subscription to named kinds restricts the client to the subscribed kinds.
+pub struct Subscriptions {
+    /// Whether the client has not subscribed to anything yet.
+    is_initial: bool,
+    /// Filter of the kinds not listed in `kinds`, `None` drops them.
+    default: Option<Filter>,
+    /// Filters of single kinds, `None` drops the kind.
+    kinds: HashMap<String, Option<Filter>>,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Subscriptions {
+            is_initial: true,
+            default: Some(Filter::default()),
+            kinds: HashMap::new(),
+        }
+    }
+}
+
+impl Subscriptions {
+    /// Subscribe to the kinds of events, or to all of them if `events` is
+    /// empty.
+    pub fn subscribe(&mut self, events: &[String], filter: &Filter) {
+        if events.is_empty() {
+            self.default = Some(filter.clone());
+            self.kinds.clear();
+        }
+        else {
+            if self.is_initial {
+                self.default = None;
+            }
+
+            for event in events {
+                self.kinds.insert(event.clone(), Some(filter.clone()));
+            }
+        }
+
+        self.is_initial = false;
+    }
+
+    /// Unsubscribe from the kinds of events, or from all of them if `events`
+    /// is empty.
+    pub fn unsubscribe(&mut self, events: &[String]) {
+        if events.is_empty() {
+            self.default = None;
+            self.kinds.clear();
+        }
+        else {
+            for event in events {
+                self.kinds.insert(event.clone(), None);
+            }
+        }
+
+        self.is_initial = false;
+    }
+
+    /// Whether the event, named `name`, is subscribed to.
+    pub fn matches(&self, name: &str, event: &Event) -> bool {
+        if self.is_initial {
+            return true
+        }
+
+        let filter = match self.kinds.get(name) {
+            Some(filter) => filter,
+            None => &self.default,
+        };
+
+        filter.as_ref().map_or(false, |filter| filter.matches(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn event(event: serde_json::Value) -> Event {
+        serde_json::from_value(event).unwrap()
+    }
+
+    fn friend_message(friend: u32) -> Event {
+        event(json!({"event": "FriendMessage", "friend": friend, "kind": "Normal",
+            "message": "hi"}))
+    }
+
+    fn friend_name(friend: u32) -> Event {
+        event(json!({"event": "FriendName", "friend": friend, "name": "name"}))
+    }
+
+    fn conference_title(conference: u32) -> Event {
+        event(json!({"event": "ConferenceTitle", "conference": conference, "peer": 0,
+            "title": "title"}))
+    }
+
+    fn connection_status() -> Event {
+        event(json!({"event": "ConnectionStatus", "status": "Udp"}))
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn filter(friends: Option<Vec<u32>>, conferences: Option<Vec<u32>>) -> Filter {
+        Filter { friends, conferences }
+    }
+
+    fn receives(subscriptions: &Subscriptions, event: &Event) -> bool {
+        subscriptions.matches(&event_name(event), event)
+    }
+
+    #[test]
+    fn every_event_is_received_before_subscribing() {
+        let subscriptions = Subscriptions::default();
+
+        assert!(receives(&subscriptions, &friend_message(0)));
+        assert!(receives(&subscriptions, &conference_title(0)));
+        assert!(receives(&subscriptions, &connection_status()));
+    }
+
+    #[test]
+    fn subscribing_to_kinds_drops_the_others() {
+        let mut subscriptions = Subscriptions::default();
+
+        subscriptions.subscribe(&names(&["FriendMessage"]), &Filter::default());
+
+        assert!(receives(&subscriptions, &friend_message(0)));
+        assert!(!receives(&subscriptions, &friend_name(0)));
+        assert!(!receives(&subscriptions, &connection_status()));
+
+        subscriptions.subscribe(&names(&["FriendName"]), &Filter::default());
+
+        assert!(receives(&subscriptions, &friend_message(0)));
+        assert!(receives(&subscriptions, &friend_name(0)));
+    }
+
+    #[test]
+    fn an_empty_list_means_all_kinds() {
+        let mut subscriptions = Subscriptions::default();
+
+        subscriptions.unsubscribe(&[]);
+        assert!(!receives(&subscriptions, &friend_message(0)));
+        assert!(!receives(&subscriptions, &connection_status()));
+
+        subscriptions.subscribe(&[], &Filter::default());
+        assert!(receives(&subscriptions, &friend_message(0)));
+        assert!(receives(&subscriptions, &connection_status()));
+    }
+
+    #[test]
+    fn unsubscribing_from_kinds_keeps_the_others() {
+        let mut subscriptions = Subscriptions::default();
+
+        subscriptions.unsubscribe(&names(&["FriendMessage"]));
+
+        assert!(!receives(&subscriptions, &friend_message(0)));
+        assert!(receives(&subscriptions, &friend_name(0)));
+        assert!(receives(&subscriptions, &conference_title(0)));
+    }
+
+    #[test]
+    fn filters_pick_friends_and_conferences() {
+        let mut subscriptions = Subscriptions::default();
+
+        subscriptions.subscribe(&[], &filter(Some(vec![1]), None));
+        subscriptions.subscribe(&names(&["ConferenceTitle"]), &filter(None, Some(vec![2])));
+
+        assert!(receives(&subscriptions, &friend_message(1)));
+        assert!(!receives(&subscriptions, &friend_message(2)));
+        assert!(receives(&subscriptions, &conference_title(2)));
+        assert!(!receives(&subscriptions, &conference_title(1)));
+        // events that concern no friend pass a friend filter
+        assert!(receives(&subscriptions, &connection_status()));
+    }
+
+    #[test]
+    fn an_empty_filter_list_drops_every_target() {
+        let no_friends = filter(Some(Vec::new()), None);
+
+        assert!(!no_friends.matches(&friend_message(0)));
+        assert!(no_friends.matches(&conference_title(0)));
+        assert!(no_friends.matches(&connection_status()));
+    }
+
+    #[test]
+    fn only_tox_events_are_known() {
+        assert!(are_known(&[]));
+        assert!(are_known(&names(&["FriendMessage", "ConferenceTitle"])));
+        assert!(!are_known(&names(&["FriendMessage", "NoSuchEvent"])));
+        // server events are always sent
+        assert!(!are_known(&names(&["Hello"])));
+        assert!(!are_known(&names(&["friendmessage"])));
+    }
+}
@@ -1,17 +1,43 @@
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender, UnboundedReceiver};
+use futures::Future;
+use futures::sync::oneshot;
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 
-use std::sync::{Arc, atomic};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, atomic};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
 use std::convert::TryInto;
-
+use std::fmt::Debug;
+
+use crate::config::AnswerQueueConfig;
+use crate::ext::{ClientRequest, ClientResponse, RequestId, ResponseEnvelope};
+use crate::logging;
+use crate::ext::{ServerEvent, ServerRequest, ServerResponse};
+use crate::permission::Policy;
+use crate::profile::ProfileStore;
+use crate::queue::{AnswerReceiver, AnswerSender, Push};
 use crate::protocol::*;
+use crate::session::Stats;
+use crate::snapshot::Tracker;
+use crate::split::Receipts;
+use crate::subscription::Subscriptions;
 
-const BOOTSTRAP_IP: &'static str = "185.25.116.107";
+const BOOTSTRAP_IP: &str = "185.25.116.107";
 const BOOTSTRAP_PORT: u16 = 33445;
-const BOOTSTRAP_KEY: &'static str =
+const BOOTSTRAP_KEY: &str =
     "DA4E4ED4B697F2E9B000EEFE3A34B554ACD3F45F5C96EAEA2516DD7FF9AF7B43";
 
-const CLIENT_NAME: &'static str = "ws-client";
+const CLIENT_NAME: &str = "ws-client";
+
+/// Requests handled between two tox iterations at most, so a flood of
+/// requests does not starve the network.
+const MAX_REQUESTS_PER_ITERATION: usize = 64;
+
+/// How often a stored profile is saved while its session runs, so a crash
+/// loses little of it.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Clone)]
 pub struct ToxGuard {
@@ -40,28 +66,236 @@ impl Drop for ToxGuard {
     }
 }
 
+/// Identifies a client among the clients attached to a session.
+pub type ClientId = u64;
+
+/// A client attached to a session.
+struct Client {
+    id: ClientId,
+    tx: AnswerSender,
+    subscriptions: Subscriptions,
+    policy: Arc<Policy>,
+}
+
+#[derive(Default)]
+struct Clients {
+    next_id: ClientId,
+    attached: Vec<Client>,
+}
+
+/// The place where a tox thread delivers its answers.
+///
+/// Any number of clients can be attached at a time, each with its own queue,
+/// subscriptions and policy. Responses go to the client that sent the
+/// request, events to every subscribed client. Clients whose connection is
+/// gone are detached on the next answer.
+#[derive(Clone)]
+pub struct AnswerSlot {
+    clients: Arc<Mutex<Clients>>,
+    queue: AnswerQueueConfig,
+    stats: Arc<Stats>,
+}
+
+impl AnswerSlot {
+    fn new(queue: AnswerQueueConfig, stats: Arc<Stats>) -> Self {
+        AnswerSlot {
+            clients: Arc::new(Mutex::new(Clients::default())),
+            queue,
+            stats,
+        }
+    }
+
+    pub fn attach(&self, policy: Arc<Policy>) -> (ClientId, AnswerReceiver) {
+        let (tx, rx) = crate::queue::channel(&self.queue);
+        let mut clients = self.clients.lock().unwrap();
+
+        let id = clients.next_id;
+        clients.next_id += 1;
+        clients.attached.push(Client {
+            id,
+            tx,
+            subscriptions: Subscriptions::default(),
+            policy,
+        });
+
+        (id, rx)
+    }
+
+    /// The policy of the client, unless it is gone.
+    pub fn policy(&self, client: ClientId) -> Option<Arc<Policy>> {
+        self.clients.lock().unwrap()
+            .attached.iter()
+            .find(|c| c.id == client)
+            .map(|c| c.policy.clone())
+    }
+
+    /// Queue the answer, returning whether the client is still there.
+    fn push(&self, client: &Client, answer: Answer) -> bool {
+        match client.tx.push(answer) {
+            Push::Queued { dropped } => {
+                self.stats.dropped(dropped);
+                true
+            },
+            Push::Closed { dropped } => {
+                self.stats.dropped(dropped);
+                false
+            },
+        }
+    }
+
+    /// Send the answer to one client.
+    fn send(&self, client: ClientId, answer: Answer) {
+        let mut clients = self.clients.lock().unwrap();
+
+        let index = match clients.attached.iter().position(|c| c.id == client) {
+            Some(index) => index,
+            None => return,
+        };
+
+        if !self.push(&clients.attached[index], answer) {
+            clients.attached.remove(index);
+        }
+    }
+
+    /// Queue the answer for every client picked by `is_recipient`.
+    fn send_where<P, A>(&self, is_recipient: P, answer: A)
+    where
+        P: Fn(&Client) -> bool,
+        A: Fn() -> Answer,
+    {
+        self.clients.lock().unwrap()
+            .attached
+            .retain(|client| !is_recipient(client) || self.push(client, answer()))
+    }
+
+    /// Send the event to every client subscribed to it.
+    fn send_event(&self, event: Event) {
+        let name = crate::subscription::event_name(&event);
+
+        self.send_where(
+            |c| c.subscriptions.matches(&name, &event),
+            || Answer::Event(event.clone())
+        )
+    }
+
+    /// Send the event to every client.
+    fn send_server_event(&self, event: ServerEvent) {
+        self.send_where(|_| true, || Answer::ServerEvent(event.clone()))
+    }
+
+    /// Send the secret key of the session, only to clients that may do
+    /// anything with the session anyway.
+    fn send_secret_key(&self, secret_key: String) {
+        self.send_where(
+            |c| c.policy.is_full(),
+            || Answer::Event(Event::SecretKey { secret_key: secret_key.clone() })
+        )
+    }
+
+    /// Whether there are clients subscribed to the event and `check` holds
+    /// for all of them.
+    fn all_recipients<F>(&self, event: &Event, check: F) -> bool
+    where
+        F: Fn(&AnswerSender) -> bool,
+    {
+        let name = crate::subscription::event_name(event);
+        let clients = self.clients.lock().unwrap();
+        let mut recipients = clients.attached.iter()
+            .filter(|client| client.subscriptions.matches(&name, event))
+            .peekable();
+
+        recipients.peek().is_some() && recipients.all(|client| check(&client.tx))
+    }
+
+    /// Whether the clients getting the file chunk are too slow to keep up
+    /// with the transfer. The fastest of them decides, so one slow client
+    /// does not pause the transfer for everyone, it reaches its hard limit
+    /// instead.
+    fn is_congested(&self, chunk: &Event) -> bool {
+        self.all_recipients(chunk, AnswerSender::is_congested)
+    }
+
+    /// Whether the fastest client getting the file chunk caught up, or no
+    /// client gets it anymore.
+    fn is_drained(&self, chunk: &Event) -> bool {
+        !self.all_recipients(chunk, |tx| !tx.is_drained())
+    }
+
+    /// Change the subscriptions of the client.
+    fn subscriptions<F: FnOnce(&mut Subscriptions)>(&self, client: ClientId, f: F) {
+        let mut clients = self.clients.lock().unwrap();
+
+        if let Some(client) = clients.attached.iter_mut().find(|c| c.id == client) {
+            f(&mut client.subscriptions)
+        }
+    }
+}
+
+/// A request of an attached client.
+pub struct Incoming {
+    pub id: Option<RequestId>,
+    pub request: ClientRequest,
+    pub client: ClientId,
+    /// Whether messages too long for toxcore are split.
+    pub split_messages: bool,
+}
+
 pub struct ToxHandle {
-    pub request_tx: std::sync::mpsc::Sender<Request>,
-    pub answer_rx: UnboundedReceiver<Answer>,
+    pub request_tx: std::sync::mpsc::Sender<Incoming>,
+    /// The client that started the session.
+    pub client: ClientId,
+    pub answer_rx: AnswerReceiver,
+    pub answers: AnswerSlot,
+    pub stats: Arc<Stats>,
     pub guard: ToxGuard,
+    /// The tox thread, which exits once the guard is dropped.
+    pub thread: std::thread::JoinHandle<()>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum Answer {
-    Response(Response),
-    Event(Event),
+    Response(ResponseEnvelope),
+    Event(#[schemars(with = "crate::mirror::Event")] Event),
+    ServerEvent(ServerEvent),
+}
+
+/// Turn the result of a tox call into a response, mapping the error with
+/// `to_response`. Errors without a counterpart in the protocol become an
+/// `UnexpectedError` instead of panicking.
+fn respond<E, P, F>(result: Result<Response, E>, to_response: F) -> ClientResponse
+where
+    E: TryInto<P> + Debug,
+    F: FnOnce(P) -> Response,
+{
+    let e = match result {
+        Ok(response) => return response.into(),
+        Err(e) => e,
+    };
+
+    let error = format!("{:?}", e);
+    match e.try_into() {
+        Ok(e) => to_response(e).into(),
+        Err(_) => ServerResponse::UnexpectedError { error }.into(),
+    }
+}
+
+fn peer_query_error<E>(e: E) -> ClientResponse
+where
+    E: TryInto<ConferencePeerQueryError> + Debug,
+{
+    respond(Err(e), |error| Response::ConferencePeerQueryError { error })
 }
 
 fn get_peer_info(
     tox: &mut rstox::core::Tox,
     conference: u32,
     peer: u32
-) -> Result<PeerInfo, ConferencePeerQueryError> {
+) -> Result<PeerInfo, ClientResponse> {
     let pk = tox.get_peer_public_key(conference, peer)
-        .map_err(|e| e.try_into().unwrap())?;
+        .map_err(peer_query_error)?;
     let name = tox.get_peer_name(conference, peer)
-        .map_err(|e| e.try_into().unwrap())?;
+        .map_err(peer_query_error)?;
     let info = PeerInfo {
         number: peer,
         public_key: format!("{}", pk),
@@ -74,9 +308,9 @@ fn get_peer_info(
 fn get_peer_list(
     tox: &mut rstox::core::Tox,
     conference: u32
-) -> Result<Vec<PeerInfo>, ConferencePeerQueryError> {
+) -> Result<Vec<PeerInfo>, ClientResponse> {
     let count = tox.conference_peer_count(conference)
-        .map_err(|e| e.try_into().unwrap())?;
+        .map_err(peer_query_error)?;
 
     let mut list = Vec::with_capacity(count as usize);
     for peer in 0..count {
@@ -108,49 +342,81 @@ fn get_conference_info(
     })
 }
 
-fn run_request(tox: &mut rstox::core::Tox, request: &Request) -> Option<Response> {
-    use Request as R;
-    use ws_tox_protocol::Friend;
+fn get_conference_list(tox: &mut rstox::core::Tox) -> Vec<ConferenceInfo> {
+    let chat_list = tox.get_chatlist();
 
-    match request {
-        R::Info => {
-            let tox_id = format!("{}", tox.get_address());
-
-            let name = tox.get_name();
-            let status = tox.get_status().into();
-            let status_message = tox.get_status_message();
-
-            let friends: Vec<_> = tox.get_friend_list()
-                .into_iter()
-                .map(|n| {
-                    let public_key =
-                        format!("{}", tox.get_friend_public_key(n).unwrap());
-                    let name = tox.get_friend_name(n).unwrap();
-                    let status = tox.get_friend_status(n).unwrap().into();
-                    let status_message = tox.get_friend_status_message(n).unwrap();
-                    let last_online = tox.get_friend_last_online(n).unwrap();
-
-                    Friend {
-                        number: n,
-                        public_key,
-                        name,
-                        status,
-                        status_message,
-                        last_online
-                    }
-                })
-                .collect();
+    let mut conferences = Vec::with_capacity(chat_list.len());
+    for c in chat_list {
+        if let Some(info) = get_conference_info(tox, c) {
+            conferences.push(info)
+        }
+    }
+
+    conferences
+}
 
-            let response = Response::Info {
-                tox_id,
+/// The own profile and the friends, as answered to `Info`.
+fn info(tox: &mut rstox::core::Tox) -> Response {
+    use ws_tox_protocol::Friend;
+
+    let tox_id = format!("{}", tox.get_address());
+
+    let name = tox.get_name();
+    let status = tox.get_status().into();
+    let status_message = tox.get_status_message();
+
+    let friends: Vec<_> = tox.get_friend_list()
+        .into_iter()
+        .filter_map(|n| {
+            let public_key =
+                format!("{}", tox.get_friend_public_key(n)?);
+            let name = tox.get_friend_name(n)?;
+            let status = tox.get_friend_status(n)?.into();
+            let status_message = tox.get_friend_status_message(n)?;
+            let last_online = tox.get_friend_last_online(n)?;
+
+            Some(Friend {
+                number: n,
+                public_key,
                 name,
                 status,
                 status_message,
-                friends
-            };
+                last_online
+            })
+        })
+        .collect();
+
+    Response::Info {
+        tox_id,
+        name,
+        status,
+        status_message,
+        friends
+    }
+}
 
-            return Some(response)
-        },
+fn invalid_field(field: &str) -> ClientResponse {
+    ServerResponse::InvalidFieldError {
+        field: field.to_owned()
+    }.into()
+}
+
+fn set_info_error(field: &str, error: rstox::core::errors::SetInfoError) -> ClientResponse {
+    ServerResponse::SetInfoError {
+        field: field.to_owned(),
+        error: error.into()
+    }.into()
+}
+
+fn run_request(tox: &mut rstox::core::Tox, request: &Request) -> ClientResponse {
+    use Request as R;
+
+    if let Err(response) = crate::validate::validate(request) {
+        return response
+    }
+
+    match request {
+        R::Info => info(tox).into(),
         R::SetInfo {
             nospam,
             name,
@@ -164,73 +430,95 @@ fn run_request(tox: &mut rstox::core::Tox, request: &Request) -> Option<Response
                 UserStatus::Busy => rstox::core::UserStatus::Busy,
             };
 
-            if let Ok(nospam) = nospam.parse() {
-                tox.set_nospam(nospam)
+            let nospam = match nospam.parse() {
+                Ok(nospam) => nospam,
+                Err(_) => return invalid_field("nospam"),
+            };
+            let friends: Result<Vec<rstox::core::PublicKey>, _> = friends.iter()
+                .map(|f| f.parse())
+                .collect();
+            let friends = match friends {
+                Ok(friends) => friends,
+                Err(_) => return invalid_field("friends"),
+            };
+
+            tox.set_nospam(nospam);
+
+            if let Err(e) = tox.set_name(name) {
+                return set_info_error("name", e)
             }
 
-            drop(tox.set_name(name));
             tox.set_status(status);
-            drop(tox.set_status_message(status_message));
 
-            for f in friends {
-                if let Ok(pk) = f.parse() {
-                    drop(tox.add_friend_norequest(&pk))
+            if let Err(e) = tox.set_status_message(status_message) {
+                return set_info_error("status_message", e)
+            }
+
+            for pk in friends {
+                if let Err(e) = tox.add_friend_norequest(&pk) {
+                    return respond(Err(e), |error| Response::AddFriendError { error })
                 }
             }
+
+            Response::Ok.into()
         },
         R::AddFriend { tox_id, message } => {
-            let address: rstox::core::Address = tox_id.parse().ok()?;
+            let address: rstox::core::Address = match tox_id.parse() {
+                Ok(address) => address,
+                Err(_) => return invalid_field("tox_id"),
+            };
 
             let response = tox.add_friend(&address, &message)
-                .map(|()| Response::Ok)
-                .unwrap_or_else(|e| Response::AddFriendError {
-                    error: e.try_into().expect("unexpected friend add error")
-                });
+                .map(|()| Response::Ok);
 
-            return Some(response)
+            respond(response, |error| Response::AddFriendError { error })
         },
         R::AddFriendNorequest { tox_id } => {
-            let address: rstox::core::PublicKey = tox_id.parse().ok()?;
+            let address: rstox::core::PublicKey = match tox_id.parse() {
+                Ok(address) => address,
+                Err(_) => return invalid_field("tox_id"),
+            };
 
             let response = tox.add_friend_norequest(&address)
-                .map(|()| Response::Ok)
-                .unwrap_or_else(|e| Response::AddFriendError {
-                    error: e.try_into().expect("unexpected friend add error")
-                });
+                .map(|()| Response::Ok);
 
-            return Some(response)
+            respond(response, |error| Response::AddFriendError { error })
         },
         R::DeleteFriend { friend } => {
             let response = tox.delete_friend(*friend)
                 .map(|()| Response::Ok)
                 .unwrap_or_else(|_| Response::FriendNotFoundError);
 
-            return Some(response)
+            response.into()
         },
         R::GetConnectionStatus => {
             let response = Response::ConnectionStatus {
                 status: tox.get_connection_status().into()
             };
 
-            return Some(response)
+            response.into()
         },
         R::GetAddress => {
             let response = Response::Address {
                 address: format!("{}", tox.get_address())
             };
 
-            return Some(response)
+            response.into()
         },
         R::GetNospam => {
             let response = Response::Nospam {
                 nospam: format!("{}", tox.get_nospam())
             };
 
-            return Some(response)
+            response.into()
         },
         R::SetNospam { nospam } => {
-            if let Ok(nospam) = nospam.parse() {
-                tox.set_nospam(nospam)
+            match nospam.parse() {
+                Ok(nospam) => {
+                    tox.set_nospam(nospam);
+                    ClientResponse::Tox(Response::Ok)
+                },
+                Err(_) => invalid_field("nospam"),
             }
         },
         R::GetPublicKey => {
@@ -238,27 +526,31 @@ fn run_request(tox: &mut rstox::core::Tox, request: &Request) -> Option<Response
                 public_key: format!("{}", tox.get_public_key())
             };
 
-            return Some(response)
+            response.into()
         },
         R::SetName { name } => {
-            drop(tox.set_name(name))
+            tox.set_name(name)
+                .map(|()| ClientResponse::Tox(Response::Ok))
+                .unwrap_or_else(|e| set_info_error("name", e))
         },
         R::GetName => {
             let response = Response::Name {
                 name: tox.get_name()
             };
 
-            return Some(response)
+            response.into()
         },
         R::SetStatusMessage { message } => {
-            drop(tox.set_status_message(message))
+            tox.set_status_message(message)
+                .map(|()| ClientResponse::Tox(Response::Ok))
+                .unwrap_or_else(|e| set_info_error("message", e))
         },
         R::GetStatusMessage => {
             let response = Response::StatusMessage {
                 status: tox.get_status_message()
             };
 
-            return Some(response)
+            response.into()
         },
         R::SetStatus { status } => {
             use rstox::core::UserStatus as S;
@@ -270,14 +562,16 @@ fn run_request(tox: &mut rstox::core::Tox, request: &Request) -> Option<Response
                 UserStatus::Busy => S::Busy
             };
 
-            tox.set_status(status)
+            tox.set_status(status);
+
+            Response::Ok.into()
         },
         R::GetStatus => {
             let response = Response::Status {
                 status: tox.get_status().into()
             };
 
-            return Some(response)
+            response.into()
         },
         R::FriendByPublicKey { public_key } => {
             let response = public_key.parse().ok()
@@ -287,14 +581,14 @@ fn run_request(tox: &mut rstox::core::Tox, request: &Request) -> Option<Response
                 })
                 .unwrap_or_else(|| Response::FriendNotFoundError);
 
-            return Some(response)
+            response.into()
         },
         R::FriendExists { friend } => {
             let response = Response::FriendExists {
                 exists: tox.friend_exists(*friend)
             };
 
-            return Some(response)
+            response.into()
         },
         R::GetFriendPublicKey { friend } => {
             let response = tox.get_friend_public_key(*friend)
@@ -303,7 +597,7 @@ fn run_request(tox: &mut rstox::core::Tox, request: &Request) -> Option<Response
                 })
                 .unwrap_or_else(|| Response::FriendNotFoundError);
 
-            return Some(response)
+            response.into()
         },
         R::GetFriendLastOnline { friend } => {
             let response = tox.get_friend_last_online(*friend)
@@ -312,7 +606,7 @@ fn run_request(tox: &mut rstox::core::Tox, request: &Request) -> Option<Response
                 })
                 .unwrap_or_else(|| Response::FriendNotFoundError);
 
-            return Some(response)
+            response.into()
         },
         R::GetFriendName { friend } => {
             let response = tox.get_friend_name(*friend)
@@ -321,7 +615,7 @@ fn run_request(tox: &mut rstox::core::Tox, request: &Request) -> Option<Response
                 })
                 .unwrap_or_else(|| Response::FriendNotFoundError);
 
-            return Some(response)
+            response.into()
         },
         R::GetFriendStatusMessage { friend } => {
             let response = tox.get_friend_status_message(*friend)
@@ -330,7 +624,7 @@ fn run_request(tox: &mut rstox::core::Tox, request: &Request) -> Option<Response
                 })
                 .unwrap_or_else(|| Response::FriendNotFoundError);
 
-            return Some(response)
+            response.into()
         },
         R::GetFriendStatus { friend } => {
             let response = tox.get_friend_status(*friend)
@@ -339,7 +633,7 @@ fn run_request(tox: &mut rstox::core::Tox, request: &Request) -> Option<Response
                 })
                 .unwrap_or_else(|| Response::FriendNotFoundError);
 
-            return Some(response)
+            response.into()
         },
         R::GetFriendConnectionStatus { friend } => {
             let response = tox.get_friend_connection_status(*friend)
@@ -348,71 +642,55 @@ fn run_request(tox: &mut rstox::core::Tox, request: &Request) -> Option<Response
                 })
                 .unwrap_or_else(|| Response::FriendNotFoundError);
 
-            return Some(response)
+            response.into()
         },
         R::SendFriendMessage { friend, kind, message } => {
             let response = tox.send_friend_message(*friend, (*kind).into(), message)
                 .map(|message_id| Response::MessageSent {
                     message_id
-                })
-                .unwrap_or_else(|e| Response::SendFriendMessageError {
-                    error: e.try_into().expect("unexpected send friend message error")
                 });
 
-            return Some(response)
+            respond(response, |error| Response::SendFriendMessageError { error })
         },
         R::ControlFile { friend, file_number, control } => {
             let response = tox.control_file(*friend, *file_number, (*control).into())
-                .map(|_| Response::Ok)
-                .unwrap_or_else(|e| Response::FileControlError {
-                    error: e.try_into().expect("unexpected file control error")
-                });
+                .map(|_| Response::Ok);
 
-            return Some(response)
+            respond(response, |error| Response::FileControlError { error })
         },
         R::SeekFile { friend, file_number, position } => {
             let response = tox.seek_file(*friend, *file_number, *position)
-                .map(|_| Response::Ok)
-                .unwrap_or_else(|e| Response::FileSeekError {
-                    error: e.try_into().expect("unexpected file seek error")
-                });
+                .map(|_| Response::Ok);
 
-            return Some(response)
+            respond(response, |error| Response::FileSeekError { error })
         },
         R::GetFileId { friend, file_number } => {
             let response = tox.get_file_id(*friend, *file_number)
                 .map(|id| Response::FileId {
                     id: format!("{}", id)
-                }).unwrap_or_else(|e|
-                    Response::FileGetError {
-                        error: e.try_into().unwrap()
-                    }
-                );
+                });
 
-            return Some(response)
+            respond(response, |error| Response::FileGetError { error })
         },
         R::SendFile { friend, kind, file_size, file_name } => {
             let response = tox.send_file(*friend, (*kind).into(), *file_size, file_name)
-                .map(|file_number| Response::FileNumber { file_number })
-                .unwrap_or_else(|e| Response::FileSendError {
-                    error: e.try_into().expect("unexpected file send error")
-                });
+                .map(|file_number| Response::FileNumber { file_number });
 
-            return Some(response)
+            respond(response, |error| Response::FileSendError { error })
         },
         R::SendFileChunk { friend, file_number, position, data } => {
             let response = tox.send_file_chunk(*friend, *file_number, *position, data)
-                .map(|_| Response::Ok)
-                .unwrap_or_else(|e| Response::FileSendChunkError {
-                    error: e.try_into().expect("unexprected file chunk send error")
-                });
+                .map(|_| Response::Ok);
 
-            return Some(response)
+            respond(response, |error| Response::FileSendChunkError { error })
         },
         R::SendAvatar { friend, file_size, file_hash } => {
             use rstox::core::FileKind;
 
-            let file_id = file_hash.parse().ok()?;
+            let file_id = match file_hash.parse() {
+                Ok(file_id) => file_id,
+                Err(_) => return invalid_field("file_hash"),
+            };
             let response =
                 tox.send_file_with_id(
                     *friend,
@@ -421,223 +699,667 @@ fn run_request(tox: &mut rstox::core::Tox, request: &Request) -> Option<Response
                     file_id,
                     "avatar.png"
                 )
-                .map(|file_number| Response::FileNumber { file_number })
-                .unwrap_or_else(|e| Response::FileSendError {
-                    error: e.try_into().unwrap()
-                });
+                .map(|file_number| Response::FileNumber { file_number });
 
-            return Some(response)
+            respond(response, |error| Response::FileSendError { error })
         },
         R::NewConference => {
-            let response = tox.new_conference()
+            tox.new_conference()
                 .map(|conference| Response::Conference {
                     conference
-                })
-                .expect("unexpected new conference error");
-
-            return Some(response)
+                }.into())
+                .unwrap_or_else(|| ServerResponse::UnexpectedError {
+                    error: "conference could not be created".to_owned()
+                }.into())
         },
         R::DeleteConference { conference } => {
-            let response = tox.delete_conference(*conference)
-                .map(|_| Response::Ok)
-                .unwrap_or_else(|| unimplemented!());
-
-            return Some(response)
+            tox.delete_conference(*conference)
+                .map(|_| Response::Ok.into())
+                .unwrap_or_else(|| ServerResponse::ConferenceNotFoundError.into())
         }
         R::GetPeerList { conference } => {
-            let response = get_peer_list(tox, *conference)
-                .map(|peers| Response::ConferencePeerList { peers })
-                .unwrap_or_else(|error| Response::ConferencePeerQueryError { error });
-
-            return Some(response)
+            get_peer_list(tox, *conference)
+                .map(|peers| Response::ConferencePeerList { peers }.into())
+                .unwrap_or_else(|response| response)
         },
         R::ConferencePeerCount { conference } => {
             let response = tox.conference_peer_count(*conference)
                 .map(|count| Response::ConferencePeerCount {
                     count
-                })
-                .unwrap_or_else(|e| Response::ConferencePeerQueryError {
-                    error: e.try_into().unwrap()
                 });
 
-            return Some(response)
+            respond(response, |error| Response::ConferencePeerQueryError { error })
         },
         R::GetPeerName { conference, peer } => {
             let response = tox.get_peer_name(*conference, *peer)
                 .map(|name| Response::ConferencePeerName {
                     name
-                })
-                .unwrap_or_else(|e| Response::ConferencePeerQueryError {
-                    error: e.try_into().unwrap()
                 });
 
-            return Some(response)
+            respond(response, |error| Response::ConferencePeerQueryError { error })
         },
         R::GetPeerPublicKey { conference, peer } => {
             let response = tox.get_peer_public_key(*conference, *peer)
                 .map(|pk| Response::ConferencePeerPublicKey {
                     public_key: format!("{}", pk)
-                })
-                .unwrap_or_else(|e| Response::ConferencePeerQueryError {
-                    error: e.try_into().unwrap()
                 });
 
-            return Some(response)
+            respond(response, |error| Response::ConferencePeerQueryError { error })
         },
         R::IsOwnPeerNumber { conference, peer_number } => {
             let response = tox.is_own_peer_number(*conference, *peer_number)
                 .map(|is_own| Response::IsOwnPeerNumber {
                     is_own
-                })
-                .unwrap_or_else(|e| Response::ConferencePeerQueryError {
-                    error: e.try_into().unwrap()
                 });
 
-            return Some(response)
+            respond(response, |error| Response::ConferencePeerQueryError { error })
         },
         R::InviteToConference { friend, conference } => {
             let response = tox.invite_to_conference(*friend, *conference)
-                .map(|_| Response::Ok)
-                .unwrap_or_else(|e| Response::ConferenceInviteError {
-                    error: e.try_into().unwrap()
-                });
+                .map(|_| Response::Ok);
 
-            return Some(response)
+            respond(response, |error| Response::ConferenceInviteError { error })
         },
         R::JoinConference { friend, cookie } => {
             let cookie = rstox::core::Cookie::from_bytes(cookie);
             let response = tox.join_conference(*friend, &cookie)
                 .map(|conference| Response::Conference {
                     conference
-                })
-                .unwrap_or_else(|e| Response::ConferenceJoinError {
-                    error: e.try_into().unwrap()
                 });
 
-            return Some(response)
+            respond(response, |error| Response::ConferenceJoinError { error })
         },
         R::SendConferenceMessage { conference, kind, message } => {
             let response = tox.send_conference_message(*conference, (*kind).into(), message)
-                .map(|_| Response::Ok)
-                .unwrap_or_else(|e| Response::ConferenceSendError {
-                    error: e.try_into().unwrap()
-                });
+                .map(|_| Response::Ok);
 
-            return Some(response)
+            respond(response, |error| Response::ConferenceSendError { error })
         },
         R::GetConferenceTitle { conference } => {
             let response = tox.get_conference_title(*conference)
                 .map(|title| Response::ConferenceTitle {
                     title
-                })
-                .unwrap_or_else(|e| Response::ConferenceTitleError {
-                    error: e.try_into().unwrap()
                 });
 
-            return Some(response)
+            respond(response, |error| Response::ConferenceTitleError { error })
         },
         R::SetConferenceTitle { conference, title } => {
             let response = tox.set_conference_title(*conference, title)
-                .map(|_| Response::Ok)
-                .unwrap_or_else(|e| Response::ConferenceTitleError {
-                    error: e.try_into().unwrap()
-                });
+                .map(|_| Response::Ok);
 
-            return Some(response)
+            respond(response, |error| Response::ConferenceTitleError { error })
         },
         R::GetConferenceList => {
-            let chat_list = tox.get_chatlist();
-
-            let mut conferences = Vec::with_capacity(chat_list.len());
-            for c in chat_list {
-                if let Some(info) = get_conference_info(tox, c) {
-                    conferences.push(info)
-                }
-            }
-
-            let response = Response::ConferenceList { conferences };
+            let response = Response::ConferenceList {
+                conferences: get_conference_list(tox)
+            };
 
-            return Some(response)
+            response.into()
         },
         R::GetConferenceType { conference } => {
-            let response = tox.get_conference_type(*conference)
+            tox.get_conference_type(*conference)
                 .map(|kind| Response::ConferenceType {
                     kind: kind.into()
-                })
-                .unwrap_or_else(|| unimplemented!());
+                }.into())
+                .unwrap_or_else(|| ServerResponse::ConferenceNotFoundError.into())
+        },
+        // variants of the protocol this server does not implement
+        _ => {
+            let request = crate::permission::request_name(request);
 
-            return Some(response)
+            ServerResponse::UnsupportedRequest { request }.into()
         },
-        _ => drop(dbg!(request)),
     }
+}
 
-    None
+/// What the requests of a client run with besides the tox instance.
+struct Context<'a> {
+    stats: &'a Stats,
+    answers: &'a AnswerSlot,
+    client: ClientId,
+    policy: &'a Policy,
+    split_messages: bool,
+    receipts: &'a RefCell<Receipts>,
+    tracker: &'a RefCell<Tracker>,
 }
 
-fn tox_loop(
+fn run_server_request(
+    tox: &mut rstox::core::Tox,
+    ctx: &Context,
+    request: &ServerRequest
+) -> ClientResponse {
+    match request {
+        // normally answered by the connection, which also disconnects
+        // incompatible clients
+        ServerRequest::Hello { protocol_version, .. } =>
+            crate::hello::negotiate(*protocol_version).unwrap_or_else(|e| e).into(),
+        ServerRequest::SessionStatus => ctx.stats.status().into(),
+        ServerRequest::Batch { requests } => {
+            let responses = requests.iter()
+                .map(|request| run_client_request(tox, ctx, request))
+                .collect();
+
+            ServerResponse::Batch { responses }.into()
+        },
+        ServerRequest::Subscribe { events, .. } |
+        ServerRequest::Unsubscribe { events } if !crate::subscription::are_known(events) =>
+            invalid_field("events"),
+        ServerRequest::Subscribe { events, filter } => {
+            ctx.answers.subscriptions(ctx.client, |s| s.subscribe(events, filter));
+
+            Response::Ok.into()
+        },
+        ServerRequest::Unsubscribe { events } => {
+            ctx.answers.subscriptions(ctx.client, |s| s.unsubscribe(events));
+
+            Response::Ok.into()
+        },
+        ServerRequest::GetLimits => crate::validate::limits().into(),
+        ServerRequest::Snapshot => ServerResponse::Snapshot {
+            info: info(tox),
+            connection_status: tox.get_connection_status().into(),
+            conferences: get_conference_list(tox),
+            friend_requests: ctx.tracker.borrow_mut().friend_requests(tox),
+            file_transfers: ctx.tracker.borrow().file_transfers(),
+        }.into(),
+    }
+}
+
+/// Send a message too long for toxcore as several messages. Other requests
+/// give `None`.
+fn run_split_request(
+    tox: &mut rstox::core::Tox,
+    ctx: &Context,
+    request: &Request
+) -> Option<ClientResponse> {
+    use crate::split::{send_parts, split};
+    use crate::validate::MAX_MESSAGE_LENGTH;
+
+    match request {
+        Request::SendFriendMessage { friend, kind, message } if message.len() > MAX_MESSAGE_LENGTH => {
+            let (message_ids, error) = send_parts(split(message, MAX_MESSAGE_LENGTH), |part|
+                tox.send_friend_message(*friend, (*kind).into(), part)
+            );
+            let error = error
+                .map(|e| respond(Err(e), |error| Response::SendFriendMessageError { error }));
+
+            if message_ids.is_empty() {
+                return error
+            }
+
+            // the parts already sent are read like any other
+            ctx.receipts.borrow_mut().track(*friend, message_ids.clone());
+
+            let response = match error {
+                Some(error) => ServerResponse::SplitMessageError {
+                    message_ids,
+                    error: Box::new(error),
+                },
+                None => ServerResponse::SplitMessageSent { message_ids },
+            };
+
+            Some(response.into())
+        },
+        Request::SendConferenceMessage { conference, kind, message } if message.len() > MAX_MESSAGE_LENGTH => {
+            let (sent, error) = send_parts(split(message, MAX_MESSAGE_LENGTH), |part|
+                tox.send_conference_message(*conference, (*kind).into(), part)
+            );
+            let error = error
+                .map(|e| respond(Err(e), |error| Response::ConferenceSendError { error }));
+
+            let response = match error {
+                // nothing was sent, like a short message that failed
+                Some(error) if sent.is_empty() => error,
+                Some(error) => ServerResponse::SplitConferenceMessageError {
+                    parts_sent: sent.len(),
+                    error: Box::new(error),
+                }.into(),
+                None => Response::Ok.into(),
+            };
+
+            Some(response)
+        },
+        _ => None,
+    }
+}
+
+fn run_client_request(
+    tox: &mut rstox::core::Tox,
+    ctx: &Context,
+    request: &ClientRequest
+) -> ClientResponse {
+    if let Err(request) = ctx.policy.check(request) {
+        return ServerResponse::PermissionDenied { request }.into()
+    }
+
+    match request {
+        ClientRequest::Tox(request) => {
+            let response = if ctx.split_messages {
+                run_split_request(tox, ctx, request)
+                    .unwrap_or_else(|| run_request(tox, request))
+            }
+            else {
+                run_request(tox, request)
+            };
+
+            ctx.tracker.borrow_mut().request(request, &response);
+
+            response
+        },
+        ClientRequest::Server(request) => run_server_request(tox, ctx, request),
+    }
+}
+
+fn save_profile(tox: &rstox::core::Tox, store: &ProfileStore, profile: &Option<String>) {
+    if let Some(ref public_key) = *profile {
+        if let Err(e) = store.save(public_key, &tox.save()) {
+            tracing::error!(error = %e, "failed to save the profile")
+        }
+    }
+}
+
+/// Why a tox instance could not be started.
+#[derive(Debug)]
+pub enum StartError {
+    /// Tox refused the options or the profile, or found no free port.
+    Create(String),
+    /// The bootstrap node could not be added.
+    Bootstrap(String),
+    /// The tox thread stopped before reporting.
+    Stopped,
+}
+
+impl std::fmt::Display for StartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StartError::Create(e) => write!(f, "tox instance could not be created: {}", e),
+            StartError::Bootstrap(e) => write!(f, "tox instance could not bootstrap: {}", e),
+            StartError::Stopped => f.write_str("tox instance stopped while starting"),
+        }
+    }
+}
+
+fn start_tox(
     secret_key: Option<rstox::core::SecretKey>,
-    request_rx: std::sync::mpsc::Receiver<Request>,
-    mut answer_tx: UnboundedSender<Answer>,
-    guard: ToxGuard,
-) {
+    savedata: &Option<Vec<u8>>,
+    nospam: Option<String>,
+) -> Result<rstox::core::Tox, StartError> {
     use rstox::core::{Tox, ToxOptions};
 
     let mut tox_options = ToxOptions::new();
 
-    if let Some(sk) = secret_key {
+    if let (Some(sk), None) = (secret_key, savedata) {
         tox_options = tox_options.set_secret_key(sk)
     }
 
-    let mut tox = Tox::new(tox_options, None).unwrap();
+    let mut tox = Tox::new(tox_options, savedata.as_ref().map(|d| &d[..]))
+        .map_err(|e| StartError::Create(format!("{:?}", e)))?;
 
-    {
-        let sk = tox.get_secret_key();
-        let ev = Event::SecretKey {
-            secret_key: format!("{}", sk)
-        };
+    if savedata.is_none() {
+        // the name is a short constant, so it always fits
+        drop(tox.set_name(CLIENT_NAME));
 
-        drop(answer_tx.try_send(Answer::Event(ev)))
+        if let Some(nospam) = nospam.and_then(|nospam| nospam.parse().ok()) {
+            tox.set_nospam(nospam)
+        }
     }
 
-    tox.set_name(CLIENT_NAME).unwrap();
-    let bootstrap_key = BOOTSTRAP_KEY.parse().unwrap();
-    tox.bootstrap(BOOTSTRAP_IP, BOOTSTRAP_PORT, bootstrap_key).unwrap();
+    let bootstrap_key = BOOTSTRAP_KEY.parse()
+        .map_err(|e| StartError::Bootstrap(format!("{:?}", e)))?;
+    tox.bootstrap(BOOTSTRAP_IP, BOOTSTRAP_PORT, bootstrap_key)
+        .map_err(|e| StartError::Bootstrap(format!("{:?}", e)))?;
+
+    Ok(tox)
+}
 
-    dbg!(format!("Server Tox ID: {}", tox.get_address()));
+fn tox_loop(
+    secret_key: Option<rstox::core::SecretKey>,
+    store: ProfileStore,
+    started_tx: oneshot::Sender<Result<(), StartError>>,
+    request_rx: std::sync::mpsc::Receiver<Incoming>,
+    answers: AnswerSlot,
+    stats: Arc<Stats>,
+    guard: ToxGuard,
+) {
+    // a stored profile is loaded together with its friends and name
+    let profile = secret_key.as_ref()
+        .and_then(|sk| store.find(&format!("{}", sk)));
+    let savedata = profile.as_ref()
+        .and_then(|pk| store.load(pk));
+    // a new profile has no save data yet, only the Tox ID it was given
+    let nospam = match (&profile, &savedata) {
+        (Some(pk), None) => store.nospam(pk),
+        _ => None,
+    };
+
+    let mut tox = match start_tox(secret_key, &savedata, nospam) {
+        Ok(tox) => tox,
+        Err(e) => {
+            drop(started_tx.send(Err(e)));
+            return
+        },
+    };
+
+    answers.send_secret_key(format!("{}", tox.get_secret_key()));
+
+    drop(started_tx.send(Ok(())));
+
+    // everything the thread logs from now on names the session
+    let tox_id = format!("{}", tox.get_address());
+    let span = tracing::info_span!("session", public_key = logging::key_prefix(&tox_id));
+    let _entered = span.enter();
+
+    tracing::info!(tox_id = logging::redact(&tox_id), "tox instance started");
+
+    // incoming transfers paused until the clients catch up, with the chunk
+    // that paused them
+    let mut paused = HashMap::new();
+    let receipts = RefCell::new(Receipts::default());
+    let tracker = RefCell::new(Tracker::default());
+    let mut saved = Instant::now();
 
     loop {
-        if guard.is_dropped() { return }
+        if guard.is_dropped() {
+            save_profile(&tox, &store, &profile);
+            tracing::info!("tox instance stopped");
+            return
+        }
 
-        if let Ok(req) = request_rx.try_recv() {
-            if let Some(resp) = run_request(&mut tox, &req) {
-                drop(answer_tx.try_send(Answer::Response(resp)))
-            }
+        if saved.elapsed() >= SAVE_INTERVAL {
+            save_profile(&tox, &store, &profile);
+            saved = Instant::now();
+        }
+
+        // requests are handled as soon as they arrive until the next
+        // iteration is due
+        let interval = Duration::from_millis(u64::from(tox.iteration_interval()));
+        let deadline = Instant::now() + interval;
+
+        for _ in 0..MAX_REQUESTS_PER_ITERATION {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+
+            let req = match request_rx.recv_timeout(timeout) {
+                Ok(req) => req,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    std::thread::sleep(timeout);
+                    break
+                },
+            };
+
+            tracing::debug!(request = %logging::request(&req.request), "request");
+
+            // the client left before its request came up
+            let policy = match answers.policy(req.client) {
+                Some(policy) => policy,
+                None => continue,
+            };
+            let ctx = Context {
+                stats: &stats,
+                answers: &answers,
+                client: req.client,
+                policy: &policy,
+                split_messages: req.split_messages,
+                receipts: &receipts,
+                tracker: &tracker,
+            };
+            let response = run_client_request(&mut tox, &ctx, &req.request);
+            let envelope = ResponseEnvelope { id: req.id, response };
+
+            answers.send(req.client, Answer::Response(envelope))
         }
 
         for ev in tox.iter() {
             if let Some(e) = crate::protocol::Event::from_tox_event(&ev) {
-                drop(answer_tx.try_send(Answer::Event(e)))
+                tracker.borrow_mut().event(&e);
+
+                if let Event::FriendReadReceipt { friend, message_id } = e {
+                    if let Some(message_ids) = receipts.borrow_mut().read(friend, message_id) {
+                        let read = ServerEvent::SplitMessageRead { friend, message_ids };
+                        answers.send_server_event(read)
+                    }
+                }
+                if let Event::FileReceiveChunk { friend, file_number, .. } = e {
+                    let transfer = (friend, file_number);
+
+                    if !paused.contains_key(&transfer) && answers.is_congested(&e) {
+                        let pause = rstox::core::FileControl::Pause;
+
+                        drop(tox.control_file(friend, file_number, pause));
+                        paused.insert(transfer, e.clone());
+                    }
+                }
+
+                answers.send_event(e)
             }
             else {
-                dbg!(ev);
+                let ev = format!("{:?}", ev);
+                tracing::debug!(event = logging::redact(&ev), "event without a protocol counterpart");
             }
         }
 
-        tox.wait();
+        paused.retain(|&(friend, file_number), chunk| {
+            let is_drained = answers.is_drained(chunk);
+            if is_drained {
+                drop(tox.control_file(friend, file_number, rstox::core::FileControl::Resume))
+            }
+
+            !is_drained
+        });
     }
 }
 
-pub fn spawn_tox(secret_key: Option<rstox::core::SecretKey>) -> ToxHandle {
-    use std::sync::mpsc;
-
-    let (request_tx, request_rx) = mpsc::channel();
-    let (answer_tx, answer_rx) = unbounded_channel();
+/// Start a tox instance on its own thread, attaching a client with the
+/// policy. Resolves once the instance is up, or with the reason it failed
+/// to start.
+pub fn spawn_tox(
+    secret_key: Option<rstox::core::SecretKey>,
+    store: ProfileStore,
+    queue: AnswerQueueConfig,
+    policy: Arc<Policy>,
+) -> impl Future<Item = ToxHandle, Error = StartError> + Send {
+    let (started_tx, started_rx) = oneshot::channel();
+    let (request_tx, request_rx) = std::sync::mpsc::channel();
+    let stats = Arc::new(Stats::new());
+    let answers = AnswerSlot::new(queue, stats.clone());
+    // attach before the thread starts so the first events are not lost
+    let (client, answer_rx) = answers.attach(policy);
     let guard = ToxGuard::new();
     let handle = guard.clone();
 
-    std::thread::spawn(move || tox_loop(secret_key, request_rx, answer_tx, handle));
+    let slot = answers.clone();
+    let counters = stats.clone();
+    let thread = std::thread::spawn(move ||
+        tox_loop(secret_key, store, started_tx, request_rx, slot, counters, handle)
+    );
+
+    started_rx.then(move |started| match started {
+        Ok(Ok(())) => Ok(ToxHandle {
+            request_tx, client, answer_rx, answers, stats, guard, thread
+        }),
+        Ok(Err(e)) => Err(e),
+        Err(oneshot::Canceled) => Err(StartError::Stopped),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::Stream;
+    use serde_json::json;
+
+    /// Run the request the way the tox thread does, for a client with full
+    /// access that splits long messages.
+    fn run(tox: &mut rstox::core::Tox, request: serde_json::Value) -> ClientResponse {
+        let stats = Arc::new(Stats::new());
+        let answers = AnswerSlot::new(AnswerQueueConfig::default(), stats.clone());
+        let policy = Arc::new(Policy::default());
+        let (client, _answer_rx) = answers.attach(policy.clone());
+        let receipts = RefCell::new(Receipts::default());
+        let tracker = RefCell::new(Tracker::default());
+        let ctx = Context {
+            stats: &stats,
+            answers: &answers,
+            client,
+            policy: &policy,
+            split_messages: true,
+            receipts: &receipts,
+            tracker: &tracker,
+        };
+
+        let parsed: ClientRequest = serde_json::from_value(request.clone())
+            .unwrap_or_else(|e| panic!("{} does not parse: {}", request, e));
+
+        run_client_request(tox, &ctx, &parsed)
+    }
+
+    fn new_tox() -> rstox::core::Tox {
+        rstox::core::Tox::new(rstox::core::ToxOptions::new(), None).unwrap()
+    }
+
+    /// The name of the response variant, e.g. `Ok`.
+    fn response_name(response: &ClientResponse) -> String {
+        crate::variant::name(response, "response")
+    }
+
+    /// Requests of every variant naming friends, files, conferences and
+    /// peers that do not exist.
+    #[test]
+    fn every_request_is_answered() {
+        let mut tox = new_tox();
+
+        for request in crate::mirror::sample_requests() {
+            let response = response_name(&run(&mut tox, request.clone()));
+
+            assert_ne!(response, "UnsupportedRequest", "{}", request);
+            assert_ne!(response, "PermissionDenied", "{}", request);
+        }
+
+        let batch = json!({"request": "Batch", "requests": crate::mirror::sample_requests()});
+        match run(&mut tox, batch) {
+            ClientResponse::Server(ServerResponse::Batch { responses }) =>
+                assert_eq!(responses.len(), crate::mirror::sample_requests().len()),
+            _ => panic!("Batch is not answered with Batch"),
+        }
+    }
+
+    #[test]
+    fn server_requests_are_answered() {
+        let mut tox = new_tox();
+        let requests = vec![
+            (json!({"request": "SessionStatus"}), "SessionStatus"),
+            (json!({"request": "GetLimits"}), "Limits"),
+            (json!({"request": "Snapshot"}), "Snapshot"),
+            (json!({"request": "Subscribe", "events": ["FriendMessage"], "friends": [42]}), "Ok"),
+            (json!({"request": "Subscribe", "events": ["NoSuchEvent"]}), "InvalidFieldError"),
+            (json!({"request": "Unsubscribe", "events": []}), "Ok"),
+        ];
+
+        for (request, expected) in requests {
+            assert_eq!(response_name(&run(&mut tox, request.clone())), expected, "{}", request);
+        }
+    }
+
+    /// Fields that cannot be parsed, are empty or too long.
+    #[test]
+    fn malformed_fields_are_answered() {
+        let mut tox = new_tox();
+        let long = "x".repeat(5000);
+        let requests = vec![
+            json!({"request": "SetInfo", "nospam": "zz", "name": long, "status": "None",
+                "status_message": "", "friends": ["zz"]}),
+            json!({"request": "SetInfo", "nospam": "0000000A", "name": "name", "status": "None",
+                "status_message": "", "friends": ["zz"]}),
+            json!({"request": "AddFriend", "tox_id": "not a tox id", "message": "hi"}),
+            json!({"request": "AddFriend", "tox_id": "0".repeat(76), "message": ""}),
+            json!({"request": "AddFriendNorequest", "tox_id": "00"}),
+            json!({"request": "SetNospam", "nospam": "zz"}),
+            json!({"request": "SetName", "name": long}),
+            json!({"request": "FriendByPublicKey", "public_key": "zz"}),
+            json!({"request": "SendFriendMessage", "friend": 42, "kind": "Normal", "message": ""}),
+            json!({"request": "SendFriendMessage", "friend": 42, "kind": "Normal", "message": long}),
+            json!({"request": "SendConferenceMessage", "conference": 42, "kind": "Normal",
+                "message": long}),
+            json!({"request": "SendFile", "friend": 42, "kind": "Data", "file_size": 0,
+                "file_name": long}),
+            json!({"request": "ControlFile", "friend": 42, "file_number": 7, "control": "Resume"}),
+            json!({"request": "SendFileChunk", "friend": 42, "file_number": 7, "position": 0,
+                "data": vec![0u8; 5000]}),
+            json!({"request": "SendAvatar", "friend": 42, "file_size": 10, "file_hash": "zz"}),
+            json!({"request": "SetConferenceTitle", "conference": 42, "title": long}),
+        ];
+
+        for request in requests {
+            let response = response_name(&run(&mut tox, request.clone()));
+
+            assert!(response.ends_with("Error"), "{} to {}", response, request);
+        }
+    }
+
+    #[test]
+    fn invalid_fields_are_named() {
+        let mut tox = new_tox();
+        let requests = vec![
+            (json!({"request": "SetInfo", "nospam": "zz", "name": "name", "status": "None",
+                "status_message": "", "friends": []}), "nospam"),
+            (json!({"request": "SetInfo", "nospam": "0000000A", "name": "name", "status": "None",
+                "status_message": "", "friends": ["zz"]}), "friends"),
+            (json!({"request": "AddFriend", "tox_id": "not a tox id", "message": "hi"}), "tox_id"),
+            (json!({"request": "AddFriendNorequest", "tox_id": "00"}), "tox_id"),
+            (json!({"request": "SetNospam", "nospam": "zz"}), "nospam"),
+            (json!({"request": "SendAvatar", "friend": 42, "file_size": 10, "file_hash": "zz"}),
+                "file_hash"),
+        ];
+
+        for (request, field) in requests {
+            let response = serde_json::to_value(run(&mut tox, request.clone())).unwrap();
+
+            let expected = json!({"response": "InvalidFieldError", "field": field});
+
+            assert_eq!(response, expected, "{}", request);
+        }
+    }
+
+    #[test]
+    fn any_conference_cookie_is_answered() {
+        let mut tox = new_tox();
+        let arbitrary: Vec<u8> = (0..=255).collect();
+
+        for length in &[0, 1, 16, 33, 34, 35, 64, 256] {
+            let cookie = &arbitrary[..*length];
+            let request = json!({"request": "JoinConference", "friend": 42, "cookie": cookie});
+            let response = response_name(&run(&mut tox, request));
+
+            assert!(response.ends_with("Error"), "{} to a cookie of {}", response, length);
+        }
+    }
+
+    #[test]
+    fn the_fastest_client_decides_on_pausing() {
+        let queue = AnswerQueueConfig {
+            capacity: 2,
+            hard_limit: 8,
+            slow_consumer: crate::queue::SlowConsumerPolicy::PauseTransfers,
+        };
+        let answers = AnswerSlot::new(queue, Arc::new(Stats::new()));
+        let (slow, _slow_rx) = answers.attach(Arc::new(Policy::default()));
+        let (fast, mut fast_rx) = answers.attach(Arc::new(Policy::default()));
+        let chunk: Event = serde_json::from_value(json!({"event": "FileReceiveChunk",
+            "friend": 0, "file_number": 0, "position": 0, "data": [1, 2, 3]})).unwrap();
+
+        for _ in 0..2 {
+            answers.send(slow, Answer::Event(chunk.clone()));
+        }
+        assert!(!answers.is_congested(&chunk));
+
+        for _ in 0..2 {
+            answers.send(fast, Answer::Event(chunk.clone()));
+        }
+        assert!(answers.is_congested(&chunk));
+        assert!(!answers.is_drained(&chunk));
 
-    ToxHandle {
-        request_tx, answer_rx, guard
+        assert_eq!(fast_rx.by_ref().take(1).wait().count(), 1);
+        assert!(answers.is_drained(&chunk));
     }
 }
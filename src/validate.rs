@@ -0,0 +1,199 @@
+//! Checks of client input against the limits of toxcore, done before the
+//! input reaches toxcore. Lengths are in bytes of UTF-8.
+
+use crate::ext::{ClientResponse, ServerResponse};
+use crate::protocol::Request;
+
+pub const MAX_NAME_LENGTH: usize = 128;
+pub const MAX_STATUS_MESSAGE_LENGTH: usize = 1007;
+pub const MAX_FRIEND_REQUEST_LENGTH: usize = 1016;
+pub const MAX_MESSAGE_LENGTH: usize = 1372;
+pub const MAX_CONFERENCE_TITLE_LENGTH: usize = 128;
+pub const MAX_FILE_NAME_LENGTH: usize = 255;
+pub const MAX_FILE_CHUNK_LENGTH: usize = 1371;
+
+/// The limits a client should enforce itself.
+pub fn limits() -> ServerResponse {
+    ServerResponse::Limits {
+        max_name_length: MAX_NAME_LENGTH,
+        max_status_message_length: MAX_STATUS_MESSAGE_LENGTH,
+        max_friend_request_length: MAX_FRIEND_REQUEST_LENGTH,
+        max_message_length: MAX_MESSAGE_LENGTH,
+        max_conference_title_length: MAX_CONFERENCE_TITLE_LENGTH,
+        max_file_name_length: MAX_FILE_NAME_LENGTH,
+        max_file_chunk_length: MAX_FILE_CHUNK_LENGTH,
+    }
+}
+
+fn max_length(field: &str, length: usize, max_length: usize) -> Result<(), ClientResponse> {
+    if length > max_length {
+        let response = ServerResponse::FieldTooLongError {
+            field: field.to_owned(),
+            max_length,
+        };
+
+        return Err(response.into())
+    }
+
+    Ok(())
+}
+
+fn not_empty(field: &str, length: usize) -> Result<(), ClientResponse> {
+    if length == 0 {
+        return Err(ServerResponse::EmptyFieldError { field: field.to_owned() }.into())
+    }
+
+    Ok(())
+}
+
+/// Check the fields of a request, the error names the first bad field.
+pub fn validate(request: &Request) -> Result<(), ClientResponse> {
+    use Request as R;
+
+    match request {
+        R::SetInfo { name, status_message, .. } => {
+            max_length("name", name.len(), MAX_NAME_LENGTH)?;
+            max_length("status_message", status_message.len(), MAX_STATUS_MESSAGE_LENGTH)
+        },
+        R::SetName { name } =>
+            max_length("name", name.len(), MAX_NAME_LENGTH),
+        R::SetStatusMessage { message } =>
+            max_length("message", message.len(), MAX_STATUS_MESSAGE_LENGTH),
+        R::AddFriend { message, .. } => {
+            not_empty("message", message.len())?;
+            max_length("message", message.len(), MAX_FRIEND_REQUEST_LENGTH)
+        },
+        R::SendFriendMessage { message, .. } |
+        R::SendConferenceMessage { message, .. } => {
+            not_empty("message", message.len())?;
+            max_length("message", message.len(), MAX_MESSAGE_LENGTH)
+        },
+        R::SetConferenceTitle { title, .. } =>
+            max_length("title", title.len(), MAX_CONFERENCE_TITLE_LENGTH),
+        R::SendFile { file_name, .. } =>
+            max_length("file_name", file_name.len(), MAX_FILE_NAME_LENGTH),
+        R::SendFileChunk { data, .. } =>
+            max_length("data", data.len(), MAX_FILE_CHUNK_LENGTH),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::{json, Value};
+
+    /// Validate the request, with the error as JSON to compare it whole.
+    fn check(request: Value) -> Result<(), Value> {
+        let parsed: Request = serde_json::from_value(request.clone())
+            .unwrap_or_else(|e| panic!("{} does not parse: {}", request, e));
+
+        validate(&parsed).map_err(|e| serde_json::to_value(e).unwrap())
+    }
+
+    fn too_long(field: &str, max_length: usize) -> Result<(), Value> {
+        Err(json!({"response": "FieldTooLongError", "field": field, "max_length": max_length}))
+    }
+
+    fn empty(field: &str) -> Result<(), Value> {
+        Err(json!({"response": "EmptyFieldError", "field": field}))
+    }
+
+    /// Requests with the text in a checked field, the field, its limit and
+    /// whether it may be empty.
+    fn text_fields() -> Vec<(fn(&str) -> Value, &'static str, usize, bool)> {
+        vec![
+            (|text| json!({"request": "SetInfo", "nospam": "0000000A", "name": text,
+                "status": "None", "status_message": "", "friends": []}),
+                "name", MAX_NAME_LENGTH, true),
+            (|text| json!({"request": "SetInfo", "nospam": "0000000A", "name": "",
+                "status": "None", "status_message": text, "friends": []}),
+                "status_message", MAX_STATUS_MESSAGE_LENGTH, true),
+            (|text| json!({"request": "SetName", "name": text}),
+                "name", MAX_NAME_LENGTH, true),
+            (|text| json!({"request": "SetStatusMessage", "message": text}),
+                "message", MAX_STATUS_MESSAGE_LENGTH, true),
+            (|text| json!({"request": "AddFriend", "tox_id": "0".repeat(76), "message": text}),
+                "message", MAX_FRIEND_REQUEST_LENGTH, false),
+            (|text| json!({"request": "SendFriendMessage", "friend": 0, "kind": "Normal",
+                "message": text}),
+                "message", MAX_MESSAGE_LENGTH, false),
+            (|text| json!({"request": "SendConferenceMessage", "conference": 0, "kind": "Action",
+                "message": text}),
+                "message", MAX_MESSAGE_LENGTH, false),
+            (|text| json!({"request": "SetConferenceTitle", "conference": 0, "title": text}),
+                "title", MAX_CONFERENCE_TITLE_LENGTH, true),
+            (|text| json!({"request": "SendFile", "friend": 0, "kind": "Data", "file_size": 0,
+                "file_name": text}),
+                "file_name", MAX_FILE_NAME_LENGTH, true),
+        ]
+    }
+
+    #[test]
+    fn text_fields_are_checked_at_their_limits() {
+        for (request, field, max_length, may_be_empty) in text_fields() {
+            let at_limit = request(&"x".repeat(max_length));
+            let over_limit = request(&"x".repeat(max_length + 1));
+            let blank = request("");
+
+            assert_eq!(check(at_limit), Ok(()), "{}", field);
+            assert_eq!(check(over_limit), too_long(field, max_length), "{}", field);
+            if may_be_empty {
+                assert_eq!(check(blank), Ok(()), "{}", field);
+            }
+            else {
+                assert_eq!(check(blank), empty(field), "{}", field);
+            }
+        }
+    }
+
+    #[test]
+    fn lengths_are_bytes_of_utf8() {
+        let name = |name: String| json!({"request": "SetName", "name": name});
+
+        assert_eq!(check(name("é".repeat(MAX_NAME_LENGTH / 2))), Ok(()));
+        assert_eq!(
+            check(name("é".repeat(MAX_NAME_LENGTH / 2) + "x")),
+            too_long("name", MAX_NAME_LENGTH)
+        );
+    }
+
+    #[test]
+    fn file_chunks_are_checked_at_their_limit() {
+        let chunk = |length: usize| json!({"request": "SendFileChunk", "friend": 0,
+            "file_number": 0, "position": 0, "data": vec![0u8; length]});
+
+        assert_eq!(check(chunk(MAX_FILE_CHUNK_LENGTH)), Ok(()));
+        assert_eq!(
+            check(chunk(MAX_FILE_CHUNK_LENGTH + 1)),
+            too_long("data", MAX_FILE_CHUNK_LENGTH)
+        );
+        // an empty chunk ends the transfer
+        assert_eq!(check(chunk(0)), Ok(()));
+    }
+
+    #[test]
+    fn the_first_bad_field_is_named() {
+        let long = "x".repeat(MAX_STATUS_MESSAGE_LENGTH + 1);
+        let request = json!({"request": "SetInfo", "nospam": "0000000A", "name": long,
+            "status": "None", "status_message": long, "friends": []});
+
+        assert_eq!(check(request), too_long("name", MAX_NAME_LENGTH));
+    }
+
+    /// Fields that do not parse are left to the tox thread, which answers
+    /// them with an `InvalidFieldError`.
+    #[test]
+    fn only_lengths_are_checked() {
+        let requests = vec![
+            json!({"request": "AddFriend", "tox_id": "not a tox id", "message": "hi"}),
+            json!({"request": "SetNospam", "nospam": "zz"}),
+            json!({"request": "SendAvatar", "friend": 0, "file_size": 10, "file_hash": "zz"}),
+        ];
+
+        for request in requests {
+            assert_eq!(check(request.clone()), Ok(()), "{}", request);
+        }
+    }
+}
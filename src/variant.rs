@@ -0,0 +1,62 @@
+//! Names of the variants of internally tagged enums, such as the protocol
+//! `Request` and `Event`, taken from serde and the schema so they match the
+//! wire format without a list to keep up to date.
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// The name of the variant, the value of its `tag` field, e.g. `GetName`
+/// for `{"request": "GetName"}`. Empty if the value has no such tag.
+pub fn name<T: Serialize>(value: &T, tag: &str) -> String {
+    serde_json::to_value(value).ok()
+        .and_then(|value| value.get(tag)?.as_str().map(|name| name.to_owned()))
+        .unwrap_or_default()
+}
+
+/// The names of all variants of an enum tagged with `tag`, read from its
+/// schema.
+pub fn names<T: JsonSchema>(tag: &str) -> Vec<String> {
+    let schema = serde_json::to_value(schemars::schema_for!(T)).unwrap_or_default();
+
+    schema.get("oneOf")
+        .and_then(|variants| variants.as_array())
+        .map(|variants| variants.iter()
+            .filter_map(|v| v.pointer(&format!("/properties/{}/enum/0", tag)))
+            .filter_map(|name| name.as_str())
+            .map(|name| name.to_owned())
+            .collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Event, Request};
+
+    #[test]
+    fn variants_are_named_by_their_tag() {
+        let chunk = Request::SendFileChunk { friend: 0, file_number: 0, position: 0, data: vec![1] };
+
+        assert_eq!(name(&Request::Info, "request"), "Info");
+        assert_eq!(name(&chunk, "request"), "SendFileChunk");
+        assert_eq!(name(&Event::ConferencePeerListChanged { conference: 0 }, "event"),
+            "ConferencePeerListChanged");
+    }
+
+    #[test]
+    fn variants_are_listed_by_their_tag() {
+        let requests = names::<crate::ext::ServerRequest>("request");
+
+        assert_eq!(requests.len(), 7);
+        assert!(requests.iter().any(|name| name == "Subscribe"));
+        assert!(names::<crate::ext::ServerRequest>("event").is_empty());
+        assert!(names::<crate::ext::Filter>("request").is_empty());
+    }
+
+    #[test]
+    fn untagged_values_have_no_name() {
+        assert_eq!(name(&Request::Info, "event"), "");
+        assert_eq!(name(&"Info", "request"), "");
+        assert_eq!(name(&serde_json::json!({"request": 1}), "request"), "");
+    }
+}